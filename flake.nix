@@ -136,7 +136,8 @@
                         };
                         kind = mkOption {
                           type = types.str;
-                          description = "Service kind";
+                          default = "none";
+                          description = "Declared kind or Consul service kind, none for neither";
                         };
                         address = mkOption {
                           type = types.str;
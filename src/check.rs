@@ -1,129 +1,483 @@
 use async_std::net::TcpStream;
-use tracing::{info,debug,warn};
-use crate::consul::RegisterAgentService;
-use crate::consul::Consul;
-use rs_consul::Consul as RsConsul;
-use bytes::Bytes;
-use std::time::Duration;
-use gethostname::gethostname;
+use tracing::debug;
+use reqwest::{Client, Method};
+use std::time::{Duration, SystemTime};
+use std::sync::Arc;
+use hyper_util::rt::TokioIo;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use tokio_rustls::TlsConnector;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Uri};
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::HealthCheckRequest;
+use std::process::Stdio;
+use tokio::process::Command;
+use crate::consul::UnavailableService;
+use crate::config::{CommandProbe, GrpcProbe, HttpProbe, Probe, ServiceConfig};
+
+// Consul truncates check output to 4KB as well
+const MAX_OUTPUT: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Health {
+    Passing,
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProbeResult {
+    pub health: Health,
+    pub output: String,
+}
+impl ProbeResult {
+    fn passing(output: String) -> Self {
+        ProbeResult { health: Health::Passing, output }
+    }
+    fn critical(output: String) -> Self {
+        ProbeResult { health: Health::Critical, output }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transition {
+    Up,
+    Down,
+}
+
+/// Consecutive probe results of a service, used to apply the rise/fall thresholds
+#[derive(Debug, Clone)]
+pub struct CheckState {
+    pub up: bool,
+    failures: u32,
+    successes: u32,
+    since: SystemTime,
+}
+impl CheckState {
+    pub fn new(up: bool) -> Self {
+        CheckState { up, failures: 0, successes: 0, since: SystemTime::now() }
+    }
+
+    /// Pick up where a previous run left a service pulled from Consul
+    pub fn unavailable(record: &UnavailableService) -> Self {
+        CheckState { up: false, failures: record.failures, successes: 0, since: record.since }
+    }
+
+    /// Record a probe result, returns a transition once `fall` failures or
+    /// `rise` successes in a row have been seen
+    pub fn observe(&mut self, health: Health, rise: u32, fall: u32) -> Option<Transition> {
+        if health == Health::Critical {
+            self.failures += 1;
+            self.successes = 0;
+            if self.up && self.failures >= fall {
+                self.up = false;
+                self.since = SystemTime::now();
+                return Some(Transition::Down);
+            }
+        } else {
+            self.successes += 1;
+            self.failures = 0;
+            if !self.up && self.successes >= rise {
+                self.up = true;
+                return Some(Transition::Up);
+            }
+        }
+        None
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn successes(&self) -> u32 {
+        self.successes
+    }
+
+    /// When the service went down
+    pub fn since(&self) -> SystemTime {
+        self.since
+    }
+
+    /// Health once the thresholds are applied: failing below `fall` is a
+    /// warning and passing below `rise` is still critical
+    pub fn health(&self, health: Health) -> Health {
+        match (self.up, health) {
+            (true, Health::Critical) => Health::Warning,
+            (false, _) => Health::Critical,
+            (true, health) => health,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ExternalCheck {
-    pub name: String,
+    pub id: String,
+    pub address: String,
+    pub port: u16,
     pub socket: String,
-    pub interval: String,
-    pub timeout: String,
+    pub probe: Probe,
+    pub timeout: Duration,
 }
 
-impl From<RegisterAgentService> for ExternalCheck {
-    fn from(service: RegisterAgentService) -> Self {
+impl From<ServiceConfig> for ExternalCheck {
+    fn from(service: ServiceConfig) -> Self {
+        let check = service.check().clone();
         ExternalCheck {
-            name: service.name,
+            id: service.id().to_string(),
             socket: format!("{}:{}", service.address, service.port),
-            interval: service.check.interval,
-            timeout: service.check.timeout,
+            address: service.address,
+            port: service.port,
+            probe: check.probe,
+            timeout: check.timeout,
         }
     }
 }
 
 impl ExternalCheck {
-    pub async fn service_available(&self) -> bool {
+    pub async fn probe(&self) -> ProbeResult {
+        let result = match &self.probe {
+            Probe::Tcp => self.tcp_available().await,
+            Probe::Http(probe) => self.http_available(probe).await,
+            Probe::Grpc(probe) => self.grpc_available(probe).await,
+            Probe::Command(probe) => return self.command_probe(probe).await,
+        };
+        match result {
+            Ok(_) => ProbeResult::passing(String::new()),
+            Err(e) => ProbeResult::critical(e),
+        }
+    }
+
+    async fn tcp_available(&self) -> Result<(), String> {
         debug!("Checking if service is available on {}", self.socket);
-        let stream = TcpStream::connect(self.socket.clone());
-        match stream.await {
-            Ok(_) => true,
-            Err(_) => {
-                warn!("Service {} is not available", &self.name);
-                false
-            } 
+        match tokio::time::timeout(self.timeout, TcpStream::connect(self.socket.clone())).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(format!("connection to {} failed: {}", self.socket, e)),
+            Err(_) => Err(format!("connection to {} timed out after {:?}", self.socket, self.timeout)),
         }
     }
-}
 
-impl From<Consul> for RsConsul {
-    fn from(consul: Consul) -> Self {
-        let config = rs_consul::Config {
-            address: consul.url,
-            token: None,
-            hyper_builder: Default::default(),
+    async fn http_available(&self, probe: &HttpProbe) -> Result<(), String> {
+        let url = probe.url(&self.address, self.port);
+        debug!("Checking if service is available on {}", url);
+        let method = Method::from_bytes(probe.method.as_bytes())
+            .map_err(|e| format!("invalid method {}: {}", probe.method, e))?;
+        let client = Client::builder()
+            .timeout(self.timeout)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let mut request = client.request(method, &url);
+        for (name, value) in &probe.headers {
+            request = request.header(name, value);
+        }
+        let response = request.send().await.map_err(|e| format!("request to {} failed: {}", url, e))?;
+        let status = response.status();
+        let expected = if probe.expected_status.is_empty() {
+            status.is_success()
+        } else {
+            probe.expected_status.contains(&status.as_u16())
         };
-        rs_consul::Consul::new(config)
+        if !expected {
+            return Err(format!("{} returned status {}", url, status));
+        }
+        if probe.body_contains.is_none() && probe.body_regex.is_none() {
+            return Ok(());
+        }
+        let body = response.text().await.map_err(|e| format!("reading body of {} failed: {}", url, e))?;
+        if let Some(needle) = &probe.body_contains {
+            if !body.contains(needle.as_str()) {
+                return Err(format!("{} body does not contain {:?}", url, needle));
+            }
+        }
+        if let Some(regex) = &probe.body_regex {
+            if !regex.is_match(&body) {
+                return Err(format!("{} body does not match {:?}", url, regex.as_str()));
+            }
+        }
+        Ok(())
     }
-}
-pub trait RsConsulExt {
-    async fn register_unavailable_service(&self, check: &ExternalCheck) -> anyhow::Result<()>;
-    async fn deregister_unavailable_service(&self, check: &ExternalCheck) -> anyhow::Result<()>;
-    async fn get_unavailable_services(&self) -> anyhow::Result<Vec<String>>;
-}
 
-impl RsConsulExt for RsConsul {
-    async fn register_unavailable_service(&self, check: &ExternalCheck) -> anyhow::Result<()> {
-        let hostname = match gethostname().into_string() {
-            Ok(h) => h,
-            Err(_) => "unknown".to_string(),
+    async fn grpc_available(&self, probe: &GrpcProbe) -> Result<(), String> {
+        let scheme = if probe.tls { "https" } else { "http" };
+        let url = format!("{}://{}", scheme, self.socket);
+        debug!("Checking if service is available on {} ({:?})", url, probe.service);
+        let channel = self.grpc_channel(probe, &url).await
+            .map_err(|e| format!("connection to {} failed: {}", url, e))?;
+        let request = HealthCheckRequest {
+            service: probe.service.clone(),
         };
-        let key = format!("consulsync/{}/{}", hostname, check.name);
-        let value = Bytes::from("unavailable");
-        let req = rs_consul::types::CreateOrUpdateKeyRequest {
-            key: key.as_str(),
-            namespace: "",
-            datacenter: "",
-            flags: 0,
-            check_and_set: None,
-            release: "",
-            acquire: "",
-        };
-        match self.create_or_update_key(req, value.to_vec()).await {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                info!("Error registering service: {}", e);
-                Ok(())
-            }
+        let response = HealthClient::new(channel)
+            .check(request)
+            .await
+            .map_err(|e| format!("health check on {} failed: {}", url, e))?;
+        match response.into_inner().status() {
+            ServingStatus::Serving => Ok(()),
+            status => Err(format!("{} reports {}", url, status.as_str_name())),
         }
     }
-    async fn deregister_unavailable_service(&self, check: &ExternalCheck) -> anyhow::Result<()> {
-        let hostname = match gethostname().into_string() {
-            Ok(h) => h,
-            Err(_) => "unknown".to_string(),
+
+    async fn grpc_channel(&self, probe: &GrpcProbe, url: &str) -> Result<Channel, String> {
+        let server_name = probe.tls_server_name.clone().unwrap_or_else(|| self.address.clone());
+        // tonic always verifies the certificate, so without verification the
+        // TLS connection is set up here and tonic speaks plain HTTP/2 over it
+        let endpoint_url = if probe.tls_skip_verify { format!("http://{}", self.socket) } else { url.to_string() };
+        let mut endpoint = Endpoint::from_shared(endpoint_url)
+            .map_err(|e| format!("invalid endpoint: {}", e))?
+            .connect_timeout(self.timeout)
+            .timeout(self.timeout);
+        if probe.tls_skip_verify {
+            let connector = insecure_tls_connector();
+            let socket = self.socket.clone();
+            let server_name = ServerName::try_from(server_name).map_err(|e| format!("invalid TLS server name: {}", e))?;
+            let connect = tower::service_fn(move |_: Uri| {
+                let (connector, socket, server_name) = (connector.clone(), socket.clone(), server_name.clone());
+                async move {
+                    let stream = tokio::net::TcpStream::connect(socket).await?;
+                    let stream = connector.connect(server_name, stream).await?;
+                    Ok::<_, std::io::Error>(TokioIo::new(stream))
+                }
+            });
+            return endpoint.connect_with_connector(connect).await.map_err(|e| e.to_string());
+        }
+        if probe.tls {
+            let mut tls = ClientTlsConfig::new().domain_name(server_name);
+            tls = match &probe.ca_file {
+                Some(ca_file) => {
+                    let pem = tokio::fs::read(ca_file).await
+                        .map_err(|e| format!("cannot read {}: {}", ca_file.display(), e))?;
+                    tls.ca_certificate(Certificate::from_pem(pem))
+                }
+                None => tls.with_native_roots(),
+            };
+            endpoint = endpoint.tls_config(tls).map_err(|e| format!("invalid TLS config: {}", e))?;
+        }
+        endpoint.connect().await.map_err(|e| e.to_string())
+    }
+
+    async fn command_probe(&self, probe: &CommandProbe) -> ProbeResult {
+        let Some((program, args)) = probe.command.split_first() else {
+            return ProbeResult::critical("empty command".to_string());
         };
-        let key = format!("consulsync/{}/{}", hostname, check.name);
-        let req = rs_consul::types::DeleteKeyRequest {
-            key: key.as_str(),
-            namespace: "",
-            datacenter: "",
-            recurse: false,
-            check_and_set: 0,
+        debug!("Checking if service is available with {:?}", probe.command);
+        let mut command = Command::new(program);
+        command.args(args).stdin(Stdio::null()).kill_on_drop(true);
+        let output = match tokio::time::timeout(self.timeout, command.output()).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => return ProbeResult::critical(format!("running {} failed: {}", program, e)),
+            Err(_) => return ProbeResult::critical(format!("{} timed out after {:?}", program, self.timeout)),
         };
-        match self.delete_key(req).await {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                info!("Error deregistering service: {}", e);
-                Ok(())
+        let mut stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if stdout.len() > MAX_OUTPUT {
+            let mut end = MAX_OUTPUT;
+            while !stdout.is_char_boundary(end) {
+                end -= 1;
             }
+            stdout.truncate(end);
         }
-    }
-    async fn get_unavailable_services(&self) -> anyhow::Result<Vec<String>> {
-        let hostname = match gethostname().into_string() {
-            Ok(h) => h,
-            Err(_) => "unknown".to_string(),
+        let health = match output.status.code() {
+            Some(0) => Health::Passing,
+            Some(1) => Health::Warning,
+            _ => Health::Critical,
         };
-        let key = format!("consulsync/{}", hostname);
-        let req = rs_consul::types::ReadKeyRequest {
-            key: key.as_str(),
-            namespace: "",
-            datacenter: "",
-            recurse: true,
-            separator: "",
-            index: None,
-            consistency: rs_consul::ConsistencyMode::Default,
-            wait: Duration::from_secs(1),
+        ProbeResult { health, output: stdout }
+    }
+}
+
+// TLS for gRPC backends with `tls_skip_verify`, HTTP/2 is negotiated as tonic expects
+fn insecure_tls_connector() -> TlsConnector {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("the ring provider supports the default protocol versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    TlsConnector::from(Arc::new(config))
+}
+
+// Accepts any certificate, the handshake signatures are still checked
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use std::fs;
+    use std::path::PathBuf;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Identity, Server, ServerTlsConfig};
+
+    // A gRPC health server on TLS with a certificate for grpc.test signed by
+    // its own CA, returns its port and the CA file
+    async fn serve_grpc(name: &str) -> (u16, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("consulsync-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["grpc.test".to_string()]).unwrap()
+            .signed_by(&key, &ca, &ca_key).unwrap();
+        let ca_file = dir.join("ca.pem");
+        fs::write(&ca_file, ca.pem()).unwrap();
+
+        let (_, health) = tonic_health::server::health_reporter();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let tls = ServerTlsConfig::new().identity(Identity::from_pem(cert.pem(), key.serialize_pem()));
+        let server = Server::builder().tls_config(tls).unwrap().add_service(health);
+        tokio::spawn(server.serve_with_incoming(TcpListenerStream::new(listener)));
+        (port, ca_file)
+    }
+
+    fn grpc_check(port: u16, probe: GrpcProbe) -> ExternalCheck {
+        ExternalCheck {
+            id: "grpc".to_string(),
+            address: "127.0.0.1".to_string(),
+            port,
+            socket: format!("127.0.0.1:{}", port),
+            probe: Probe::Grpc(probe),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn tls_probe() -> GrpcProbe {
+        GrpcProbe { service: String::new(), tls: true, ca_file: None, tls_server_name: None, tls_skip_verify: false }
+    }
+
+    #[tokio::test]
+    async fn grpc_tls_verifies_with_the_ca_file_and_server_name() {
+        let (port, ca_file) = serve_grpc("grpc-ca").await;
+        // Neither the system roots nor the address match the certificate
+        let result = grpc_check(port, tls_probe()).probe().await;
+        assert_eq!(result.health, Health::Critical, "{}", result.output);
+        let probe = GrpcProbe { ca_file: Some(ca_file.clone()), ..tls_probe() };
+        let result = grpc_check(port, probe).probe().await;
+        assert_eq!(result.health, Health::Critical, "{}", result.output);
+        let probe = GrpcProbe { ca_file: Some(ca_file), tls_server_name: Some("grpc.test".to_string()), ..tls_probe() };
+        let result = grpc_check(port, probe).probe().await;
+        assert_eq!(result.health, Health::Passing, "{}", result.output);
+    }
+
+    #[tokio::test]
+    async fn grpc_tls_skip_verify_accepts_any_certificate() {
+        let (port, _) = serve_grpc("grpc-skip").await;
+        let probe = GrpcProbe { tls_skip_verify: true, ..tls_probe() };
+        let result = grpc_check(port, probe).probe().await;
+        assert_eq!(result.health, Health::Passing, "{}", result.output);
+    }
+
+    #[test]
+    fn goes_down_after_fall_failures_in_a_row() {
+        let mut state = CheckState::new(true);
+        assert_eq!(state.observe(Health::Critical, 2, 3), None);
+        assert_eq!(state.observe(Health::Critical, 2, 3), None);
+        assert_eq!(state.observe(Health::Critical, 2, 3), Some(Transition::Down));
+        assert!(!state.up);
+        assert_eq!(state.failures(), 3);
+        // Further failures do not repeat the transition
+        assert_eq!(state.observe(Health::Critical, 2, 3), None);
+        assert_eq!(state.failures(), 4);
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let mut state = CheckState::new(true);
+        state.observe(Health::Critical, 1, 2);
+        assert_eq!(state.observe(Health::Passing, 1, 2), None);
+        assert_eq!(state.observe(Health::Critical, 1, 2), None);
+        assert!(state.up);
+    }
+
+    #[test]
+    fn comes_back_up_after_rise_successes_in_a_row() {
+        let mut state = CheckState::new(false);
+        assert_eq!(state.observe(Health::Passing, 2, 1), None);
+        assert_eq!(state.observe(Health::Critical, 2, 1), None);
+        assert_eq!(state.observe(Health::Passing, 2, 1), None);
+        assert_eq!(state.observe(Health::Passing, 2, 1), Some(Transition::Up));
+        assert!(state.up);
+    }
+
+    #[test]
+    fn warning_counts_as_success() {
+        let mut state = CheckState::new(false);
+        assert_eq!(state.observe(Health::Warning, 1, 1), Some(Transition::Up));
+        assert_eq!(state.observe(Health::Warning, 1, 1), None);
+    }
+
+    #[test]
+    fn health_applies_the_thresholds() {
+        let mut state = CheckState::new(true);
+        state.observe(Health::Critical, 2, 2);
+        assert_eq!(state.health(Health::Critical), Health::Warning);
+        state.observe(Health::Critical, 2, 2);
+        assert_eq!(state.health(Health::Critical), Health::Critical);
+        state.observe(Health::Passing, 2, 2);
+        assert_eq!(state.health(Health::Passing), Health::Critical);
+        state.observe(Health::Passing, 2, 2);
+        assert_eq!(state.health(Health::Passing), Health::Passing);
+        assert_eq!(state.health(Health::Warning), Health::Warning);
+    }
+
+    #[test]
+    fn unavailable_record_restores_the_state() {
+        let since = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let record = UnavailableService {
+            id: "web".to_string(),
+            since,
+            last_error: "connection refused".to_string(),
+            failures: 5,
+            address: "10.0.0.1".to_string(),
+            port: 80,
+            version: "0.2.0".to_string(),
         };
-        let resp = self.read_key(req).await?;
-        let unavailable_services: Vec<String> = resp.iter().filter_map(|r| {
-            let parts: Vec<&str> = r.key.split('/').collect();
-            Some(parts[parts.len()-1].to_string())
-        }).collect();
-        info!("Unavailable services: {:?}", unavailable_services);
-        Ok(unavailable_services)
+        let mut state = CheckState::unavailable(&record);
+        assert!(!state.up);
+        assert_eq!(state.failures(), 5);
+        assert_eq!(state.since(), since);
+        // Still down, so no second Down transition and the start is kept
+        assert_eq!(state.observe(Health::Critical, 1, 1), None);
+        assert_eq!(state.since(), since);
     }
 }
@@ -1,59 +1,489 @@
 use figment::{Figment, providers::{Format, Toml}};
-use serde::{Serialize, Deserialize};
+use std::fs;
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de::{Error as _, IgnoredAny};
 use tracing::{info, debug};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use figment::providers::Env;
 use std::collections::HashMap;
+use std::sync::LazyLock;
+use std::time::Duration;
+use humantime_serde::re::humantime::format_duration;
+use regex::Regex;
+use reqwest::Method;
 
 use crate::consul::Consul;
 use crate::consul::AgentService;
+use crate::template::Renderer;
 
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Config {
     pub consul: Consul,
     pub log_level: Option<String>,
+    /// How often managed services are compared against Consul to fix drift
+    #[serde(default = "default_reconcile_interval", with = "humantime_serde")]
+    pub reconcile_interval: Duration,
+    /// Deregister the managed services and clear their state when stopping
+    #[serde(default)]
+    pub deregister_on_shutdown: bool,
+    /// Upper bound on the health probes running at the same time
+    #[serde(default = "default_max_concurrent_checks")]
+    pub max_concurrent_checks: usize,
     pub services: Vec<ServiceConfig>,
+    #[serde(default)]
     pub external_kinds: Vec<ExternalKindConfig>,
+    #[serde(default)]
     pub kinds: Vec<KindConfig>,
+    /// Values templates can use as `{{ vars.<name> }}`
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+}
+
+fn default_reconcile_interval() -> Duration {
+    Duration::from_secs(60)
 }
 
-#[derive(Debug, Deserialize, Clone)]
+fn default_max_concurrent_checks() -> usize {
+    16
+}
+
+/// A kind whose service fields live in their own TOML, YAML or JSON file
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct ExternalKindConfig {
     pub name: String,
     pub filename: String,
 }
-#[derive(Debug, Deserialize, Clone)]
+impl ExternalKindConfig {
+    /// Read the kind file, the format is picked from the extension
+    pub fn load(&self) -> anyhow::Result<KindConfig> {
+        let path = Path::new(&self.filename);
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("kind {}: cannot read {}: {}", self.name, self.filename, e))?;
+        let kind: Result<KindConfig, String> = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => path_to_error(toml::Deserializer::new(&content)),
+            Some("yaml") | Some("yml") => path_to_error(serde_yaml::Deserializer::from_str(&content)),
+            Some("json") => path_to_error(&mut serde_json::Deserializer::from_str(&content)),
+            _ => anyhow::bail!("kind {}: {} must end in .toml, .yaml, .yml or .json", self.name, self.filename),
+        };
+        let kind = kind.map_err(|e| anyhow::anyhow!("kind {}: invalid {}: {}", self.name, self.filename, e))?;
+        Ok(KindConfig { name: self.name.clone(), ..kind })
+    }
+}
+
+// Deserialize and name the key that failed, serde_json does not on its own
+// while serde_yaml already starts its errors with the path
+fn path_to_error<'de, T: Deserialize<'de>, D: Deserializer<'de>>(deserializer: D) -> Result<T, String>
+where
+    D::Error: std::fmt::Display,
+{
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let inner = e.inner().to_string();
+        match e.path().to_string().as_str() {
+            "." => inner,
+            path if inner.starts_with(&format!("{}: ", path)) => inner,
+            path => format!("{}: {}", path, inner),
+        }
+    })
+}
+
+/// A `[[kinds]]` entry or the content of an external kind file. The port
+/// and address replace the service's own, the check is used when the service
+/// has none, tags are added unless the service sets the same key and the
+/// service's meta, weights and tagged addresses win
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct KindConfig {
+    /// Taken from the `[[external_kinds]]` entry for a kind file
+    #[serde(default)]
     pub name: String,
+    // Allowed so a kind file can say what it is
+    #[serde(rename = "kind")]
+    _kind: Option<IgnoredAny>,
+    /// Kinds this one is layered on, a later kind wins over an earlier one
+    #[serde(default)]
+    pub extends: Vec<String>,
+    pub port: Option<u16>,
+    pub address: Option<String>,
     #[serde(default)]
     pub tags: Vec<String>,
+    #[serde(default)]
+    pub meta: HashMap<String, String>,
+    pub weights: Option<Weights>,
+    #[serde(default)]
+    pub tagged_addresses: HashMap<String, TaggedAddress>,
+    pub check: Option<CheckConfig>,
+}
+
+impl KindConfig {
+    // `self` layered on `base`, with the same precedence a service has over its kinds
+    fn overlay(&self, base: &KindConfig) -> KindConfig {
+        let mut meta = base.meta.clone();
+        meta.extend(self.meta.clone());
+        let mut tagged_addresses = base.tagged_addresses.clone();
+        tagged_addresses.extend(self.tagged_addresses.clone());
+        KindConfig {
+            name: self.name.clone(),
+            _kind: None,
+            extends: Vec::new(),
+            port: self.port.or(base.port),
+            address: self.address.clone().or_else(|| base.address.clone()),
+            tags: merge_tags(&self.tags, &base.tags),
+            meta,
+            weights: self.weights.or(base.weights),
+            tagged_addresses,
+            check: self.check.clone().or_else(|| base.check.clone()),
+        }
+    }
 }
 
-#[derive(Debug, Serialize,Deserialize, Clone)]
+// Service kinds Consul knows about, passed through when no kind has the name
+const CONSUL_SERVICE_KINDS: [&str; 5] = ["connect-proxy", "mesh-gateway", "terminating-gateway", "ingress-gateway", "api-gateway"];
+
+// Flatten kinds and everything they extend, a later kind wins over an earlier
+// one. Each kind is applied once, after the kinds it extends, so a kind reached
+// twice through a diamond does not undo the kinds in between
+fn resolve_kinds(names: &[&str], kinds: &HashMap<String, KindConfig>) -> anyhow::Result<KindConfig> {
+    let mut order = Vec::new();
+    for name in names {
+        kind_order(name, kinds, &mut Vec::new(), &mut order)?;
+    }
+    Ok(order.into_iter().fold(KindConfig::default(), |base, kind| kind.overlay(&base)))
+}
+
+// Push the kinds `name` extends depth first, then `name` itself. `chain` holds
+// the kinds being ordered to report cycles
+fn kind_order<'a>(
+    name: &str,
+    kinds: &'a HashMap<String, KindConfig>,
+    chain: &mut Vec<String>,
+    order: &mut Vec<&'a KindConfig>,
+) -> anyhow::Result<()> {
+    if chain.iter().any(|kind| kind == name) {
+        anyhow::bail!("kind cycle: {} -> {}", chain.join(" -> "), name);
+    }
+    let Some(kind) = kinds.get(name) else {
+        match chain.last() {
+            Some(parent) => anyhow::bail!("kind {} extends unknown kind {}", parent, name),
+            None => anyhow::bail!("unknown kind {}", name),
+        }
+    };
+    if order.iter().any(|ordered| ordered.name == kind.name) {
+        return Ok(());
+    }
+    chain.push(name.to_string());
+    for parent in &kind.extends {
+        kind_order(parent, kinds, chain, order)?;
+    }
+    chain.pop();
+    order.push(kind);
+    Ok(())
+}
+
+/// How much traffic a service gets from DNS while passing or warning
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct Weights {
+    #[serde(alias = "passing")]
+    pub passing: u16,
+    #[serde(alias = "warning")]
+    pub warning: u16,
+}
+// What Consul uses when no weights are given
+impl Default for Weights {
+    fn default() -> Self {
+        Weights { passing: 1, warning: 1 }
+    }
+}
+
+/// An extra address the service is reachable on, such as `lan` or `wan`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct TaggedAddress {
+    #[serde(alias = "address")]
+    pub address: String,
+    #[serde(alias = "port")]
+    pub port: u16,
+}
+
+#[derive(Debug, Serialize,Deserialize, Clone, PartialEq)]
 pub struct ServiceConfig {
+    /// Consul service ID, the name when unset. Needed to run several
+    /// instances of the same service
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     pub name: String,
+    /// A declared kind or a Consul service kind such as `connect-proxy`,
+    /// empty or `none` for neither
+    #[serde(default)]
     pub kind: String,
+    /// More kinds applied after `kind`, a later kind wins over an earlier one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kinds: Vec<String>,
     pub port: u16,
     pub address: String,
     #[serde(default)]
     pub tags: Vec<String>,
+    #[serde(default)]
+    pub meta: HashMap<String, String>,
+    pub weights: Option<Weights>,
+    #[serde(default)]
+    pub tagged_addresses: HashMap<String, TaggedAddress>,
+    /// How the service is probed, taken from its kinds when it sets none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check: Option<CheckConfig>,
 }
 impl Default for ServiceConfig {
     fn default() -> Self {
         ServiceConfig {
+            id: None,
             name: "".to_string(),
             kind: "".to_string(),
+            kinds: Vec::new(),
             port: 0,
             address: "".to_string(),
             tags: Vec::new(),
+            meta: HashMap::new(),
+            weights: None,
+            tagged_addresses: HashMap::new(),
+            check: None,
+        }
+    }
+}
+
+/// The `[services.check]` table, how the service health is probed
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CheckConfig {
+    #[serde(flatten)]
+    pub probe: Probe,
+    #[serde(default = "default_check_interval", with = "humantime_serde")]
+    pub interval: Duration,
+    #[serde(default = "default_check_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Consecutive successful probes before an unavailable service is marked up
+    #[serde(default = "default_threshold")]
+    pub rise: u32,
+    /// Consecutive failed probes before a service is marked down
+    #[serde(default = "default_threshold")]
+    pub fall: u32,
+    /// Register a Consul TTL check instead and push the probe results to it,
+    /// the service then stays registered while unhealthy
+    #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<Duration>,
+}
+
+impl Default for CheckConfig {
+    fn default() -> Self {
+        CheckConfig {
+            probe: Probe::default(),
+            interval: default_check_interval(),
+            timeout: default_check_timeout(),
+            rise: default_threshold(),
+            fall: default_threshold(),
+            ttl: None,
+        }
+    }
+}
+
+impl CheckConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.interval.is_zero() {
+            anyhow::bail!("check interval must be greater than 0");
+        }
+        if self.timeout.is_zero() {
+            anyhow::bail!("check timeout must be greater than 0");
+        }
+        if self.rise == 0 || self.fall == 0 {
+            anyhow::bail!("check rise and fall must be at least 1");
+        }
+        if let Probe::Grpc(probe) = &self.probe {
+            if !probe.tls && (probe.ca_file.is_some() || probe.tls_server_name.is_some() || probe.tls_skip_verify) {
+                anyhow::bail!("check ca_file, tls_server_name and tls_skip_verify need tls = true");
+            }
+        }
+        if let Some(ttl) = self.ttl {
+            // Consul would mark the check critical between two pushes
+            if ttl <= self.interval {
+                anyhow::bail!("check ttl {} must be longer than the interval {}", format_duration(ttl), format_duration(self.interval));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_check_interval() -> Duration {
+    Duration::from_secs(10)
+}
+fn default_check_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+fn default_threshold() -> u32 {
+    1
+}
+
+#[derive(Debug, Serialize, Clone, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Probe {
+    /// The service accepts TCP connections on its address and port
+    #[default]
+    Tcp,
+    Http(HttpProbe),
+    /// The standard `grpc.health.v1.Health/Check` reports SERVING
+    Grpc(GrpcProbe),
+    /// A command run by consulsync, exit code 0 is passing, 1 warning and anything else critical
+    Command(CommandProbe),
+}
+
+// `type` is optional and defaults to tcp, so a check table that only sets
+// the interval or thresholds is still a TCP check. The probe gets every key
+// `CheckConfig` does not know, so it is the one rejecting typos
+impl<'de> Deserialize<'de> for Probe {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(tag = "type", rename_all = "lowercase")]
+        enum Tagged {
+            Tcp,
+            Http(HttpProbe),
+            Grpc(GrpcProbe),
+            Command(CommandProbe),
+        }
+        // A JSON value holds what TOML, YAML and JSON kind files can all say, nulls included
+        let mut table = serde_json::Map::deserialize(deserializer)?;
+        let probe_type = table.entry("type").or_insert_with(|| "tcp".into());
+        // A unit variant takes any key, a tcp probe has none of its own
+        if probe_type.as_str() == Some("tcp") {
+            if let Some(key) = table.keys().find(|key| *key != "type") {
+                return Err(D::Error::custom(format!("unknown field `{}` in a tcp check", key)));
+            }
+        }
+        let probe = Tagged::deserialize(serde_json::Value::Object(table)).map_err(D::Error::custom)?;
+        Ok(match probe {
+            Tagged::Tcp => Probe::Tcp,
+            Tagged::Http(probe) => Probe::Http(probe),
+            Tagged::Grpc(probe) => Probe::Grpc(probe),
+            Tagged::Command(probe) => Probe::Command(probe),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HttpProbe {
+    #[serde(default = "default_http_scheme")]
+    pub scheme: String,
+    #[serde(default = "default_http_method", deserialize_with = "http_method")]
+    pub method: String,
+    #[serde(default = "default_http_path")]
+    pub path: String,
+    /// Accepted status codes, any 2xx when empty
+    #[serde(default)]
+    pub expected_status: Vec<u16>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub body_contains: Option<String>,
+    /// Compiled when the config is read, so a typo fails `validate` rather than every probe
+    #[serde(default, with = "body_regex", skip_serializing_if = "Option::is_none")]
+    pub body_regex: Option<Regex>,
+}
+// Regex has no PartialEq, the patterns are compared instead
+impl PartialEq for HttpProbe {
+    fn eq(&self, other: &Self) -> bool {
+        self.scheme == other.scheme
+            && self.method == other.method
+            && self.path == other.path
+            && self.expected_status == other.expected_status
+            && self.headers == other.headers
+            && self.body_contains == other.body_contains
+            && self.body_regex.as_ref().map(Regex::as_str) == other.body_regex.as_ref().map(Regex::as_str)
+    }
+}
+impl HttpProbe {
+    pub fn url(&self, address: &str, port: u16) -> String {
+        format!("{}://{}:{}{}", self.scheme, address, port, self.path)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcProbe {
+    /// Service name sent in the health request, the whole server when empty
+    #[serde(default)]
+    pub service: String,
+    #[serde(default)]
+    pub tls: bool,
+    /// CA certificate the backend is verified with instead of the system roots.
+    /// Consul verifies its own check with the agent's TLS settings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+    /// Name the certificate has to be valid for, the service address by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_server_name: Option<String>,
+    /// Accept any certificate, for backends with a self-signed one
+    #[serde(default)]
+    pub tls_skip_verify: bool,
+}
+impl GrpcProbe {
+    pub fn target(&self, address: &str, port: u16) -> String {
+        if self.service.is_empty() {
+            format!("{}:{}", address, port)
+        } else {
+            format!("{}:{}/{}", address, port, self.service)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CommandProbe {
+    /// Program and its arguments, not run through a shell
+    pub command: Vec<String>,
+}
+
+fn http_method<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let method = String::deserialize(deserializer)?;
+    Method::from_bytes(method.as_bytes())
+        .map_err(|e| D::Error::custom(format!("invalid method {:?}: {}", method, e)))?;
+    Ok(method)
+}
+
+mod body_regex {
+    use regex::Regex;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(regex: &Option<Regex>, serializer: S) -> Result<S::Ok, S::Error> {
+        match regex {
+            Some(regex) => serializer.serialize_some(regex.as_str()),
+            None => serializer.serialize_none(),
         }
     }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+        let Some(pattern) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        Regex::new(&pattern)
+            .map(Some)
+            .map_err(|e| D::Error::custom(format!("invalid body_regex {:?}: {}", pattern, e)))
+    }
+}
+
+fn default_http_scheme() -> String {
+    "http".to_string()
+}
+fn default_http_method() -> String {
+    "GET".to_string()
+}
+fn default_http_path() -> String {
+    "/".to_string()
 }
 impl PartialEq<AgentService> for ServiceConfig {
     fn eq(&self, other: &AgentService) -> bool {
         // Check that everything is the same
-        if self.name != other.id {
+        if self.id() != other.id {
+            return false;
+        }
+        if self.name != other.service {
             return false;
         }
         if self.port != other.port {
@@ -67,73 +497,92 @@ impl PartialEq<AgentService> for ServiceConfig {
         if self.tags != tags {
             return false;
         }
-        if self.kind != other.kind {
+        if self.consul_kind() != other.kind {
+            return false;
+        }
+        if self.meta != other.configured_meta() {
+            return false;
+        }
+        if self.weights.unwrap_or_default() != other.weights {
+            return false;
+        }
+        if self.tagged_addresses != other.configured_tagged_addresses(self) {
+            return false;
+        }
+        if other.check_changed(self) {
             return false;
         }
         true
     }
 }
 impl ServiceConfig {
-    // Merge function to merge service type configuration into service configuration
-    fn merge_from(&mut self, service_type_config: HashMap<String, serde_yaml::Value>) {
-        for (key, value) in service_type_config {
-            // Update service configuration with service type configuration
-            match key.as_str() {
-                "name" | "kind" => continue, // Skip merging name and kind fields
-                _ => {
-                    self.update_field(key, value); // Update other fields
-                }
-            }
+    pub fn id(&self) -> &str {
+        self.id.as_deref().unwrap_or(&self.name)
+    }
+
+    /// The kind registered in Consul, empty unless `kind` is one of Consul's
+    /// own service kinds
+    pub fn consul_kind(&self) -> &str {
+        if CONSUL_SERVICE_KINDS.contains(&self.kind.as_str()) {
+            &self.kind
+        } else {
+            ""
         }
     }
-    fn update_field(&mut self, key: String, value: serde_yaml::Value) {
-        match key.as_str() {
-            "port" => {
-                self.port = value.as_u64().unwrap() as u16;
-            }
-            "address" => {
-                self.address = value.as_str().unwrap().to_string();
-            }
-            "tags" => {
-                let new_tags: Vec<String> = value.as_sequence().unwrap().iter().map(|v| v.as_str().unwrap().to_string()).collect();
-                self.update_tags(new_tags);
-                self.replace_service_name();
-            }
-            _ => {
-                panic!("Unknown field: {}", key);
-            }
+
+    /// The check of the service or of its kinds, a TCP check with the
+    /// default timings when neither sets one
+    pub fn check(&self) -> &CheckConfig {
+        static DEFAULT_CHECK: LazyLock<CheckConfig> = LazyLock::new(CheckConfig::default);
+        self.check.as_ref().unwrap_or(&DEFAULT_CHECK)
+    }
+
+    fn apply_kind(&mut self, kind: &KindConfig) {
+        if let Some(port) = kind.port {
+            self.port = port;
+        }
+        if let Some(address) = &kind.address {
+            self.address = address.clone();
         }
+        if self.check.is_none() {
+            self.check = kind.check.clone();
+        }
+        self.update_tags(kind.tags.clone());
+        self.replace_service_name();
+        for (key, value) in &kind.meta {
+            self.meta.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+        for (key, value) in &kind.tagged_addresses {
+            self.tagged_addresses.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+        self.weights = self.weights.or(kind.weights);
     }
     fn update_tags(&mut self, tags: Vec<String>) {
-        let mut result_list: Vec<&str> = Vec::new();
         info!("Checking tags {:?}",tags);
-        for tag in &tags {
-            if let Some((key2, _)) = extract_key_value(&tag) {
-                let mut found = false;
-                for strong_tag in &self.tags {
-                    if let Some((key1, _)) = extract_key_value(&strong_tag) {
-                        if key1 == key2 {
-                            found = true;
-                        } 
-                        result_list.push(strong_tag);
-                    } else {
-                        result_list.push(strong_tag);
-                    }
-                }
-                if !found {
-                    result_list.push(tag);
-                }
-            } else {
-                result_list.push(tag);
-            }
-        }
-        info!("Result list {:?}",result_list);
-        self.tags = result_list.iter().map(|s| s.to_string()).collect();
+        self.tags = merge_tags(&self.tags, &tags);
+        info!("Result list {:?}",self.tags);
     }
     fn replace_service_name(&mut self) {
         self.tags = self.tags.iter().map(|tag| tag.replace("SERVICE_NAME", &self.name)).collect();
     }
 }
+// The strong tags come first, a weak tag is added unless a strong tag is the
+// same or sets the same key=value key
+fn merge_tags(strong: &[String], weak: &[String]) -> Vec<String> {
+    let mut result_list = strong.to_vec();
+    for tag in weak {
+        let overridden = match extract_key_value(tag) {
+            Some((key, _)) => strong.iter().any(|strong_tag| {
+                matches!(extract_key_value(strong_tag), Some((strong_key, _)) if strong_key == key)
+            }),
+            None => strong.contains(tag),
+        };
+        if !overridden && !result_list.contains(tag) {
+            result_list.push(tag.clone());
+        }
+    }
+    result_list
+}
 fn extract_key_value(input: &str) -> Option<(&str, &str)> {
     let parts: Vec<&str> = input.trim().splitn(2, '=').collect();
     match parts.as_slice() {
@@ -143,19 +592,14 @@ fn extract_key_value(input: &str) -> Option<(&str, &str)> {
 }
 
 impl Config {
-    fn get_kind_file(&self, kind: &str) -> String { 
-        let kind_config = match self.external_kinds.iter().find(|k| k.name == kind) {
-            Some(kind_config) => kind_config.filename.clone(),
-            None => "".to_string(),
-        };
-        kind_config
-    }
-    fn get_kind_tags(&self, kind: &str) -> Vec<String> {
-        let kind_tags = match self.kinds.iter().find(|k| k.name == kind) {
-            Some(kind_config) => kind_config.tags.clone(),
-            None => Vec::new(),
-        };
-        kind_tags
+    /// Files besides the config file whose changes trigger a reload
+    pub fn watched_files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = self.external_kinds.iter().map(|k| PathBuf::from(&k.filename)).collect();
+        // A rotated ACL token is applied without a restart
+        if let Some(token_file) = &self.consul.token_file {
+            files.push(token_file.clone());
+        }
+        files
     }
 
 }
@@ -169,22 +613,278 @@ pub fn read(config_file: &PathBuf) -> anyhow::Result<Config> {
         .extract()?;
 
     debug!("Consul url {}", config.consul.url);
-    for i in 0..config.services.len() {
-        let service = &config.services[i];
-        let service_type = &service.kind;
-        let service_type_config_file = config.get_kind_file(service_type); 
-        let service_tags = config.get_kind_tags(service_type);
-        info!("Service type is {:?}", service_type_config_file);
-        let service_type_config: HashMap<String, serde_yaml::Value> = Figment::new()
-            .merge(Toml::file(service_type_config_file))
-            .extract()?;
-        if let Some(service_config) = config.services.get_mut(i) {
-            service_config.merge_from(service_type_config.clone());
-            service_config.update_field("tags".to_string(),service_tags.clone().into());
+    // Every declared kind file has to load, even when no service uses it yet.
+    // A `[[kinds]]` entry with the same name is layered on the file
+    let mut kinds: HashMap<String, KindConfig> = HashMap::new();
+    for external_kind in &config.external_kinds {
+        kinds.insert(external_kind.name.clone(), external_kind.load()?);
+    }
+    for kind in &config.kinds {
+        if kind.name.is_empty() {
+            anyhow::bail!("a [[kinds]] entry in {} has no name", config_file.display());
+        }
+        let kind = match kinds.get(&kind.name) {
+            Some(file) => KindConfig {
+                extends: [file.extends.clone(), kind.extends.clone()].concat(),
+                ..kind.overlay(file)
+            },
+            None => kind.clone(),
+        };
+        kinds.insert(kind.name.clone(), kind);
+    }
+    for name in kinds.keys() {
+        resolve_kinds(&[name], &kinds)?;
+    }
+    let renderer = Renderer::new(&config.vars);
+    for service in &mut config.services {
+        // `kind` is also the Consul service kind, so it may be one of those
+        // instead of a declared kind. Every entry of `kinds` has to exist
+        let mut names: Vec<&str> = Vec::new();
+        if kinds.contains_key(&service.kind) {
+            names.push(&service.kind);
+        } else if !matches!(service.kind.as_str(), "" | "none") && service.consul_kind().is_empty() {
+            anyhow::bail!("service {}: unknown kind {}, declare it in [[kinds]] or [[external_kinds]]", service.name, service.kind);
+        }
+        names.extend(service.kinds.iter().map(String::as_str));
+        let mut kind = resolve_kinds(&names, &kinds).map_err(|e| anyhow::anyhow!("service {}: {}", service.name, e))?;
+        renderer.render(service, &mut kind)?;
+        info!("Applying kinds of service {}", service.name);
+        service.apply_kind(&kind);
+    }
+
+    let mut ids = Vec::new();
+    for service in &config.services {
+        if ids.contains(&service.id()) {
+            anyhow::bail!("duplicate service id {:?}, set `id` to tell the instances of {:?} apart", service.id(), service.name);
         }
+        ids.push(service.id());
+        service.check().validate().map_err(|e| anyhow::anyhow!("service {}: {}", service.id(), e))?;
     }
 
     debug!("Read config is {:?}", config);
 
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(toml: &str) -> Result<CheckConfig, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    fn kinds(toml: &str) -> HashMap<String, KindConfig> {
+        #[derive(Deserialize)]
+        struct Kinds {
+            kinds: Vec<KindConfig>,
+        }
+        let kinds: Kinds = toml::from_str(toml).unwrap();
+        kinds.kinds.into_iter().map(|kind| (kind.name.clone(), kind)).collect()
+    }
+
+    fn resolve(name: &str, kinds: &HashMap<String, KindConfig>) -> anyhow::Result<KindConfig> {
+        resolve_kinds(&[name], kinds)
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn later_extends_win_and_the_kind_wins_over_all() {
+        let kinds = kinds(r#"
+            [[kinds]]
+            name = "app"
+            extends = ["first", "second"]
+            tags = ["app"]
+            [[kinds]]
+            name = "first"
+            port = 1
+            address = "10.0.0.1"
+            tags = ["entry=first", "first"]
+            meta = { owner = "first", team = "first" }
+            [[kinds]]
+            name = "second"
+            port = 2
+            tags = ["entry=second"]
+            meta = { owner = "second" }
+        "#);
+        let app = resolve("app", &kinds).unwrap();
+        assert_eq!(app.port, Some(2));
+        assert_eq!(app.address.as_deref(), Some("10.0.0.1"));
+        assert_eq!(app.tags, tags(&["app", "entry=second", "first"]));
+        assert_eq!(app.meta["owner"], "second");
+        assert_eq!(app.meta["team"], "first");
+        assert!(app.extends.is_empty());
+    }
+
+    #[test]
+    fn overlay_keeps_the_base_only_where_unset() {
+        let kinds = kinds(r#"
+            [[kinds]]
+            name = "top"
+            weights = { passing = 5, warning = 1 }
+            check = { type = "http", path = "/top" }
+            [[kinds]]
+            name = "base"
+            port = 80
+            weights = { passing = 1, warning = 1 }
+            tagged_addresses = { wan = { address = "203.0.113.5", port = 80 } }
+            check = { interval = "1s" }
+        "#);
+        let top = kinds["top"].overlay(&kinds["base"]);
+        assert_eq!(top.port, Some(80));
+        assert_eq!(top.weights, Some(Weights { passing: 5, warning: 1 }));
+        assert_eq!(top.tagged_addresses["wan"].address, "203.0.113.5");
+        assert!(matches!(&top.check.unwrap().probe, Probe::Http(probe) if probe.path == "/top"));
+        let base = KindConfig::default().overlay(&kinds["base"]);
+        assert_eq!(base.check.unwrap().interval, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn diamond_extends_apply_the_shared_kind_once() {
+        let kinds = kinds(r#"
+            [[kinds]]
+            name = "top"
+            extends = ["left", "right"]
+            [[kinds]]
+            name = "left"
+            extends = ["base"]
+            tags = ["layer=left"]
+            [[kinds]]
+            name = "right"
+            extends = ["base"]
+            meta = { side = "right" }
+            [[kinds]]
+            name = "base"
+            port = 8080
+            tags = ["layer=base", "shared"]
+            meta = { side = "base" }
+        "#);
+        let top = resolve("top", &kinds).unwrap();
+        assert_eq!(top.port, Some(8080));
+        // `base` comes before both sides, so it cannot undo `left`
+        assert_eq!(top.tags, tags(&["layer=left", "shared"]));
+        assert_eq!(top.meta["side"], "right");
+        // Same for a service listing both sides as `kind` and `kinds`
+        assert_eq!(resolve_kinds(&["left", "right"], &kinds).unwrap(), KindConfig { name: "right".to_string(), ..top });
+    }
+
+    #[test]
+    fn extends_cycles_and_unknown_kinds_are_errors() {
+        let kinds = kinds(r#"
+            [[kinds]]
+            name = "a"
+            extends = ["b"]
+            [[kinds]]
+            name = "b"
+            extends = ["c"]
+            [[kinds]]
+            name = "c"
+            extends = ["a"]
+            [[kinds]]
+            name = "self"
+            extends = ["self"]
+            [[kinds]]
+            name = "broken"
+            extends = ["missing"]
+        "#);
+        assert_eq!(resolve("a", &kinds).unwrap_err().to_string(), "kind cycle: a -> b -> c -> a");
+        assert_eq!(resolve("self", &kinds).unwrap_err().to_string(), "kind cycle: self -> self");
+        assert_eq!(resolve("broken", &kinds).unwrap_err().to_string(), "kind broken extends unknown kind missing");
+        assert_eq!(resolve("nope", &kinds).unwrap_err().to_string(), "unknown kind nope");
+    }
+
+    #[test]
+    fn strong_tags_win_by_key_and_are_not_repeated() {
+        let merged = merge_tags(
+            &tags(&["traefik.enable=false", "web", "path = /api"]),
+            &tags(&["traefik.enable=true", "web", "path=/", "extra", "extra=1"]),
+        );
+        assert_eq!(merged, tags(&["traefik.enable=false", "web", "path = /api", "extra", "extra=1"]));
+        assert_eq!(merge_tags(&[], &tags(&["a", "a"])), tags(&["a"]));
+    }
+
+    #[test]
+    fn kind_check_only_applies_to_services_without_one() {
+        let kind: KindConfig = toml::from_str("check = { type = \"http\", path = \"/kind\" }").unwrap();
+        let mut service: ServiceConfig = toml::from_str(r#"
+            name = "blog"
+            port = 80
+            address = "127.0.0.1"
+            check = { type = "http", path = "/mine" }
+        "#).unwrap();
+        service.apply_kind(&kind);
+        assert!(matches!(&service.check().probe, Probe::Http(probe) if probe.path == "/mine"));
+        let mut service = ServiceConfig { check: None, ..service };
+        assert_eq!(service.check().probe, Probe::Tcp);
+        service.apply_kind(&kind);
+        assert!(matches!(&service.check().probe, Probe::Http(probe) if probe.path == "/kind"));
+    }
+
+    #[test]
+    fn only_consul_service_kinds_are_registered() {
+        let service = ServiceConfig { name: "blog".to_string(), kind: "connect-proxy".to_string(), ..Default::default() };
+        assert_eq!(service.consul_kind(), "connect-proxy");
+        for kind in ["", "none", "traefik_authelia"] {
+            let service = ServiceConfig { kind: kind.to_string(), ..service.clone() };
+            assert_eq!(service.consul_kind(), "");
+        }
+    }
+
+    #[test]
+    fn service_tags_win_over_kind_tags() {
+        let mut service: ServiceConfig = toml::from_str(r#"
+            name = "blog"
+            port = 80
+            address = "127.0.0.1"
+            tags = ["traefik.enable=false", "blog"]
+        "#).unwrap();
+        let kind: KindConfig = toml::from_str(r#"
+            tags = ["traefik.enable=true", "traefik.http.routers.SERVICE_NAME.tls=true", "blog"]
+        "#).unwrap();
+        service.apply_kind(&kind);
+        assert_eq!(service.tags, tags(&["traefik.enable=false", "blog", "traefik.http.routers.blog.tls=true"]));
+    }
+
+    #[test]
+    fn check_table_takes_the_probe_and_schedule_keys() {
+        let config = check("type = \"http\"\npath = \"/health\"\nexpected_status = [200]\ninterval = \"2s\"\nrise = 2").unwrap();
+        assert!(matches!(&config.probe, Probe::Http(probe) if probe.path == "/health"));
+        assert_eq!(config.interval, Duration::from_secs(2));
+        assert_eq!(config.rise, 2);
+        assert_eq!(check("interval = \"3s\"").unwrap().probe, Probe::Tcp);
+    }
+
+    #[test]
+    fn check_table_rejects_unknown_keys() {
+        let error = check("intervall = \"1s\"").unwrap_err().to_string();
+        assert!(error.contains("unknown field `intervall` in a tcp check"), "{}", error);
+        let error = check("type = \"http\"\nexepcted_status = [200]").unwrap_err().to_string();
+        assert!(error.contains("unknown field `exepcted_status`"), "{}", error);
+        assert!(check("type = \"grpc\"\ntsl = true").is_err());
+        assert!(check("type = \"command\"\ncommand = [\"true\"]\nargs = []").is_err());
+    }
+
+    #[test]
+    fn yaml_kind_errors_name_the_path_once() {
+        let error = path_to_error::<KindConfig, _>(serde_yaml::Deserializer::from_str("port: port")).unwrap_err();
+        assert!(error.starts_with("port: invalid type"), "{}", error);
+        let error = path_to_error::<KindConfig, _>(serde_yaml::Deserializer::from_str("check:\n  type: http\n  exepcted_status: [200]"))
+            .unwrap_err();
+        assert!(error.starts_with("check: unknown field `exepcted_status`"), "{}", error);
+        let error = path_to_error::<KindConfig, _>(&mut serde_json::Deserializer::from_str("{\"port\": \"x\"}")).unwrap_err();
+        assert!(error.starts_with("port: invalid type"), "{}", error);
+    }
+
+    #[test]
+    fn kind_file_checks_take_null_values() {
+        let kind: KindConfig = path_to_error(serde_yaml::Deserializer::from_str("check:\n  type: http\n  body_contains: null\n  ttl: null"))
+            .unwrap();
+        assert!(matches!(&kind.check.unwrap().probe, Probe::Http(probe) if probe.body_contains.is_none()));
+        let kind: KindConfig = path_to_error(&mut serde_json::Deserializer::from_str("{\"check\": {\"body_regex\": null, \"type\": \"http\"}}"))
+            .unwrap();
+        assert!(matches!(&kind.check.unwrap().probe, Probe::Http(probe) if probe.body_regex.is_none()));
+    }
+}
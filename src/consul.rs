@@ -1,35 +1,30 @@
 use tracing::{info,debug,warn};
-use reqwest::{Client, header};
-use serde_json;
+use reqwest::{Certificate, Client, Identity, RequestBuilder, Response, StatusCode, Url, header};
+use rand::Rng;
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 
-use crate::config::ServiceConfig;
+use gethostname::gethostname;
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Service {
-    #[serde(flatten)]
-    pub data: HashMap<String, Vec<String>>,
-}
-impl fmt::Display for Service {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for (service, tags) in self.data.iter() {
-            write!(f, "Service : {} has tags : \n", service)?;
-            for tag in tags {
-                write!(f, "{}\n", tag)?;
-            }
-            writeln!(f, "")?;
-        }
-        Ok(())
-    }
-}
+use crate::check::{ExternalCheck, Health, ProbeResult};
+use crate::config::{GrpcProbe, HttpProbe, Probe, ServiceConfig, TaggedAddress, Weights};
+
+/// Meta key naming the consulsync instance that registered a service, so
+/// instances sharing an agent leave each other's services alone
+pub const INSTANCE_META: &str = "consulsync_instance";
+/// Meta key holding a hash of the registered check, Consul does not return
+/// the check definition with the service
+pub const CHECK_META: &str = "consulsync_check";
+
+// Consul fills these in from the service address when they are not given
+const AUTOMATIC_TAGGED_ADDRESSES: [&str; 4] = ["lan_ipv4", "wan_ipv4", "lan_ipv6", "wan_ipv6"];
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct AgentServiceResponse {
-    #[serde(flatten)]
-    pub data: Vec<AgentService>,
-}
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct AgentService {
@@ -42,15 +37,20 @@ pub struct AgentService {
     pub meta: HashMap<String, String>,
     pub port: u16,
     pub address: String,
-    pub tagged_addresses: serde_json::Value, 
-    pub weights: HashMap<String, u16>,
+    #[serde(default)]
+    pub tagged_addresses: HashMap<String, TaggedAddress>,
+    #[serde(default)]
+    pub weights: Weights,
     pub enable_tag_override: bool,
     pub datacenter: String,
 }
 impl PartialEq<ServiceConfig> for AgentService {
     fn eq(&self, other: &ServiceConfig) -> bool {
         // Check that everything is the same
-        if self.id != other.name {
+        if self.id != other.id() {
+            return false;
+        }
+        if self.service != other.name {
             return false;
         }
         if self.port != other.port {
@@ -64,183 +64,899 @@ impl PartialEq<ServiceConfig> for AgentService {
         if tags != other.tags {
             return false;
         }
-        if self.kind != other.kind {
+        if self.kind != other.consul_kind() {
+            return false;
+        }
+        if self.configured_meta() != other.meta {
+            return false;
+        }
+        if self.weights != other.weights.unwrap_or_default() {
+            return false;
+        }
+        if self.configured_tagged_addresses(other) != other.tagged_addresses {
+            return false;
+        }
+        if self.check_changed(other) {
             return false;
         }
         true
     }
 }
+impl AgentService {
+    /// Meta without the keys consulsync adds to tell its instances apart and
+    /// to track the check
+    pub fn configured_meta(&self) -> HashMap<String, String> {
+        let mut meta = self.meta.clone();
+        meta.remove(INSTANCE_META);
+        meta.remove(CHECK_META);
+        meta
+    }
+
+    /// Whether the check registered with the service differs from the config,
+    /// a service registered without the hash is taken as changed
+    pub fn check_changed(&self, other: &ServiceConfig) -> bool {
+        self.meta.get(CHECK_META) != Some(&check_hash(&service_check(other)))
+    }
+
+    /// Tagged addresses without the ones Consul adds on its own for the
+    /// service address, unless the config sets them
+    pub fn configured_tagged_addresses(&self, other: &ServiceConfig) -> HashMap<String, TaggedAddress> {
+        self.tagged_addresses.iter()
+            .filter(|(key, _)| {
+                !AUTOMATIC_TAGGED_ADDRESSES.contains(&key.as_str()) || other.tagged_addresses.contains_key(*key)
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Field level differences between the registered service and its config,
+    /// the counterpart of the `PartialEq<ServiceConfig>` comparison.
+    pub fn diff(&self, other: &ServiceConfig) -> Vec<String> {
+        let mut diff = Vec::new();
+        if self.service != other.name {
+            diff.push(format!("name: {} -> {}", self.service, other.name));
+        }
+        if self.port != other.port {
+            diff.push(format!("port: {} -> {}", self.port, other.port));
+        }
+        if self.address != other.address {
+            diff.push(format!("address: {} -> {}", self.address, other.address));
+        }
+        if self.kind != other.consul_kind() {
+            diff.push(format!("kind: {} -> {}", self.kind, other.consul_kind()));
+        }
+        let tags: Vec<&String> = self.tags.iter().filter(|tag| *tag != "nixconsul").collect();
+        for tag in &other.tags {
+            if !tags.contains(&tag) {
+                diff.push(format!("tags: + {}", tag));
+            }
+        }
+        for tag in &tags {
+            if !other.tags.contains(tag) {
+                diff.push(format!("tags: - {}", tag));
+            }
+        }
+        if diff.is_empty() && tags != other.tags.iter().collect::<Vec<_>>() {
+            diff.push("tags: order changed".to_string());
+        }
+        let current_meta = self.configured_meta();
+        let mut meta: Vec<_> = other.meta.iter().collect();
+        meta.sort();
+        for (key, value) in meta {
+            match current_meta.get(key) {
+                None => diff.push(format!("meta: + {}={}", key, value)),
+                Some(current) if current != value => diff.push(format!("meta: {}: {} -> {}", key, current, value)),
+                Some(_) => (),
+            }
+        }
+        let mut removed: Vec<_> = current_meta.keys().filter(|key| !other.meta.contains_key(*key)).collect();
+        removed.sort();
+        for key in removed {
+            diff.push(format!("meta: - {}", key));
+        }
+        let weights = other.weights.unwrap_or_default();
+        if self.weights != weights {
+            diff.push(format!(
+                "weights: passing {} warning {} -> passing {} warning {}",
+                self.weights.passing, self.weights.warning, weights.passing, weights.warning
+            ));
+        }
+        let tagged_addresses = self.configured_tagged_addresses(other);
+        let mut keys: Vec<_> = tagged_addresses.keys().chain(other.tagged_addresses.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            match (tagged_addresses.get(key), other.tagged_addresses.get(key)) {
+                (None, Some(new)) => diff.push(format!("tagged_addresses: + {}={}:{}", key, new.address, new.port)),
+                (Some(_), None) => diff.push(format!("tagged_addresses: - {}", key)),
+                (Some(current), Some(new)) if current != new => diff.push(format!(
+                    "tagged_addresses: {}: {}:{} -> {}:{}",
+                    key, current.address, current.port, new.address, new.port
+                )),
+                _ => (),
+            }
+        }
+        if self.check_changed(other) {
+            match service_check(other) {
+                Some(check) => diff.push(format!("check: {}", check)),
+                None => diff.push("check: none".to_string()),
+            }
+        }
+        diff
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct AgentCheck {
+    #[serde(rename = "CheckID")]
+    pub check_id: String,
+    pub name: String,
+    pub status: String,
+    #[serde(default)]
+    pub output: String,
+    #[serde(rename = "ServiceID", default)]
+    pub service_id: String,
+    #[serde(default)]
+    pub service_name: String,
+}
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct ServiceCheck {
-    #[serde(rename = "TCP")]
-    pub tcp: String,
-    pub interval: String,
-    pub timeout: String,
+    #[serde(rename = "CheckID", skip_serializing_if = "Option::is_none")]
+    pub check_id: Option<String>,
+    #[serde(rename = "TTL", skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<String>,
+    #[serde(rename = "TCP", skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<String>,
+    #[serde(rename = "HTTP", skip_serializing_if = "Option::is_none")]
+    pub http: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    // Ordered so the check hash does not depend on the map order
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub header: BTreeMap<String, Vec<String>>,
+    #[serde(rename = "GRPC", skip_serializing_if = "Option::is_none")]
+    pub grpc: Option<String>,
+    #[serde(rename = "GRPCUseTLS", skip_serializing_if = "Option::is_none")]
+    pub grpc_use_tls: Option<bool>,
+    #[serde(rename = "TLSServerName", skip_serializing_if = "Option::is_none")]
+    pub tls_server_name: Option<String>,
+    #[serde(rename = "TLSSkipVerify", skip_serializing_if = "Option::is_none")]
+    pub tls_skip_verify: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<String>,
 }
 impl ServiceCheck {
     pub fn new(tcp: &str) -> Self {
         ServiceCheck {
-            tcp: tcp.to_string(),
-            interval: "10s".to_string(),
-            timeout: "5s".to_string(),
+            check_id: None,
+            ttl: None,
+            tcp: Some(tcp.to_string()),
+            http: None,
+            method: None,
+            header: BTreeMap::new(),
+            grpc: None,
+            grpc_use_tls: None,
+            tls_server_name: None,
+            tls_skip_verify: None,
+            interval: Some("10s".to_string()),
+            timeout: Some("5s".to_string()),
+        }
+    }
+    /// A check consulsync keeps alive itself with the probe results
+    pub fn ttl(service_id: &str, ttl: Duration) -> Self {
+        ServiceCheck {
+            check_id: Some(ttl_check_id(service_id)),
+            ttl: Some(go_duration(ttl)),
+            tcp: None,
+            interval: None,
+            timeout: None,
+            ..ServiceCheck::new("")
+        }
+    }
+    pub fn http(url: &str, probe: &HttpProbe) -> Self {
+        ServiceCheck {
+            tcp: None,
+            http: Some(url.to_string()),
+            method: Some(probe.method.clone()),
+            header: probe.headers.iter().map(|(k, v)| (k.clone(), vec![v.clone()])).collect(),
+            ..ServiceCheck::new("")
+        }
+    }
+    pub fn grpc(target: &str, probe: &GrpcProbe) -> Self {
+        ServiceCheck {
+            tcp: None,
+            grpc: Some(target.to_string()),
+            grpc_use_tls: Some(probe.tls),
+            tls_server_name: probe.tls_server_name.clone(),
+            // Left out unless set so checks registered before keep their hash
+            tls_skip_verify: probe.tls_skip_verify.then_some(true),
+            ..ServiceCheck::new("")
         }
     }
 }
 
+impl fmt::Display for ServiceCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ttl) = &self.ttl {
+            return write!(f, "ttl {}", ttl);
+        }
+        match (&self.tcp, &self.http, &self.grpc) {
+            (Some(tcp), _, _) => write!(f, "tcp {}", tcp)?,
+            (_, Some(http), _) => write!(f, "http {} {}", self.method.as_deref().unwrap_or("GET"), http)?,
+            (_, _, Some(grpc)) => write!(f, "grpc {}", grpc)?,
+            _ => write!(f, "none")?,
+        }
+        if let (Some(interval), Some(timeout)) = (&self.interval, &self.timeout) {
+            write!(f, " every {} timeout {}", interval, timeout)?;
+        }
+        Ok(())
+    }
+}
+
+/// The check Consul runs for a service, none for command checks which
+/// consulsync runs itself
+fn service_check(service: &ServiceConfig) -> Option<ServiceCheck> {
+    let config = service.check();
+    let check = match (&config.ttl, &config.probe) {
+        (Some(ttl), _) => Some(ServiceCheck::ttl(service.id(), *ttl)),
+        (None, Probe::Tcp) => Some(ServiceCheck::new(&format!("{}:{}", &service.address, &service.port))),
+        (None, Probe::Http(probe)) => Some(ServiceCheck::http(&probe.url(&service.address, service.port), probe)),
+        (None, Probe::Grpc(probe)) => Some(ServiceCheck::grpc(&probe.target(&service.address, service.port), probe)),
+        // Script checks are usually disabled on agents, consulsync runs the command itself
+        (None, Probe::Command(_)) => None,
+    };
+    check.map(|check| match check.ttl {
+        Some(_) => check,
+        None => ServiceCheck {
+            interval: Some(go_duration(config.interval)),
+            timeout: Some(go_duration(config.timeout)),
+            ..check
+        },
+    })
+}
+
+// FNV-1a over the JSON the check is registered with, unlike the std hasher
+// it gives the same value across builds
+fn check_hash(check: &Option<ServiceCheck>) -> String {
+    let json = serde_json::to_vec(check).unwrap_or_default();
+    let hash = json.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+// Consul parses durations the Go way, which humantime's "1m 30s" is not
+fn go_duration(duration: Duration) -> String {
+    format!("{}ms", duration.as_millis())
+}
+
+pub fn ttl_check_id(service_id: &str) -> String {
+    format!("service:{}", service_id)
+}
+
+// Sessions are named after the instance holding them
+const SESSION_PREFIX: &str = "consulsync-";
+
+fn session_name(instance_id: &str) -> String {
+    format!("{}{}", SESSION_PREFIX, instance_id)
+}
+
+/// Record stored under `<kv_prefix>/<instance_id>/<id>` while a service is pulled
+/// from Consul because its probe fails
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UnavailableService {
+    #[serde(skip)]
+    pub id: String,
+    #[serde(with = "humantime_serde")]
+    pub since: SystemTime,
+    pub last_error: String,
+    pub failures: u32,
+    pub address: String,
+    pub port: u16,
+    pub version: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SessionRequest {
+    name: String,
+    #[serde(rename = "TTL")]
+    ttl: String,
+    behavior: String,
+    node_checks: Vec<String>,
+    lock_delay: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionResponse {
+    #[serde(rename = "ID")]
+    id: String,
+}
+
+// An entry of `/v1/session/list`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SessionEntry {
+    #[serde(rename = "ID")]
+    id: String,
+    name: String,
+}
+
+// An entry of `/v1/kv/<prefix>?recurse`, the value is base64 encoded
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct KvPair {
+    key: String,
+    value: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct RegisterAgentService {
+    #[serde(rename = "ID")]
+    pub id: String,
     pub kind: String,
     pub name: String,
     pub tags: Vec<String>,
     pub meta: HashMap<String, String>,
     pub port: u16,
     pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weights: Option<Weights>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub tagged_addresses: HashMap<String, TaggedAddress>,
     pub enable_tag_override: bool,
-    pub check: ServiceCheck,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub check: Option<ServiceCheck>,
 }
 impl RegisterAgentService {
     pub fn _new(name: &str, kind: &str, port: u16, address: &str, tags: Vec<String>) -> Self {
         RegisterAgentService {
+            id: name.to_string(),
             name: name.to_string(),
             kind: kind.to_string(),
             port,
             address: address.to_string(),
             tags,
             meta: HashMap::new(),
+            weights: None,
+            tagged_addresses: HashMap::new(),
             enable_tag_override: true,
-            check: ServiceCheck::new(&format!("{}:{}", address, port)),
+            check: Some(ServiceCheck::new(&format!("{}:{}", address, port))),
         }
     }
 }
 impl From<ServiceConfig> for RegisterAgentService {
     fn from(service: ServiceConfig) -> Self {
+        let check = service_check(&service);
+        let kind = service.consul_kind().to_string();
         RegisterAgentService {
+            id: service.id().to_string(),
             name: service.name,
-            kind: service.kind,
+            kind,
             port: service.port,
-            address: service.address.clone(),
+            address: service.address,
             tags: service.tags,
-            meta: HashMap::new(),
+            meta: service.meta,
+            weights: service.weights,
+            tagged_addresses: service.tagged_addresses,
             enable_tag_override: true,
-            check: ServiceCheck::new(&format!("{}:{}", &service.address, &service.port)),
+            check,
         }
     }
 }
 
+fn tls_error(e: reqwest::Error) -> ClientError {
+    ClientError::Config(format!("Invalid TLS settings: {}", e))
+}
+
+async fn read_file(path: &Path) -> Result<Vec<u8>, ClientError> {
+    tokio::fs::read(path).await.map_err(|e| ClientError::Config(format!("Unable to read {:?}: {}", path, e)))
+}
 
 #[derive(Debug)]
-pub struct ClientError {
-    pub message: String,
+pub enum ClientError {
+    /// The request never got a response from the agent
+    Transport(reqwest::Error),
+    /// The agent answered with an unexpected status, the body holds Consul's explanation
+    Status {
+        status: StatusCode,
+        body: String,
+    },
+    /// The response could not be decoded or the request encoded
+    Decode(serde_json::Error),
+    /// The token is missing or lacks the required ACL permissions
+    AclDenied(String),
+    NotFound(String),
+    /// The `[consul]` settings could not be turned into a client
+    Config(String),
+}
+impl ClientError {
+    /// Whether sending the same request again may succeed.
+    /// ACL and validation errors will keep failing until the config changes.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ClientError::Transport(_) => true,
+            ClientError::Status { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            },
+            _ => false,
+        }
+    }
 }
 impl From<reqwest::Error> for ClientError {
     fn from(e: reqwest::Error) -> Self {
-        ClientError {
-            message: format!("Request error: {}", e),
-        }
+        ClientError::Transport(e)
     }
 }
 impl From<serde_json::Error> for ClientError {
     fn from(err: serde_json::Error) -> Self {
-        ClientError {
-            message: format!("Formating error: {}", err),
-        }
+        ClientError::Decode(err)
     }
 }
 impl std::fmt::Display for ClientError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.message)
+        match self {
+            ClientError::Transport(e) => write!(f, "Request error: {}", e),
+            ClientError::Status { status, body } => write!(f, "Consul returned {}: {}", status, body.trim()),
+            ClientError::Decode(e) => write!(f, "Formating error: {}", e),
+            ClientError::AclDenied(body) => write!(f, "ACL denied: {}", body.trim()),
+            ClientError::NotFound(body) => write!(f, "Not found: {}", body.trim()),
+            ClientError::Config(message) => write!(f, "{}", message),
+        }
+    }
+}
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Transport(e) => Some(e),
+            ClientError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Turn any non-2xx answer into a ClientError carrying the body Consul sent back
+async fn check_response(response: Response) -> Result<Response, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    match status {
+        StatusCode::FORBIDDEN => Err(ClientError::AclDenied(body)),
+        StatusCode::NOT_FOUND => Err(ClientError::NotFound(body)),
+        _ => Err(ClientError::Status { status, body }),
     }
 }
-impl std::error::Error for ClientError {}
 
-#[derive(Debug,Serialize,Deserialize,Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    #[serde(with = "humantime_serde")]
+    pub base_delay: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_delay: Duration,
+    pub jitter: bool,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: true,
+        }
+    }
+}
+impl RetryPolicy {
+    /// Delay before the next try after `attempt` failures: exponential from
+    /// `base_delay`, capped at `max_delay`. Jitter picks a random delay between
+    /// half and the full value so instances don't retry in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self.base_delay.saturating_mul(1 << exponent).min(self.max_delay);
+        if self.jitter && !delay.is_zero() {
+            let millis = delay.as_millis() as u64;
+            Duration::from_millis(rand::thread_rng().gen_range(millis / 2..=millis))
+        } else {
+            delay
+        }
+    }
+}
+
+#[derive(Serialize,Deserialize,Clone)]
 pub struct Consul {
     #[serde(skip)]
     client: Client,
     pub url: String,
+    #[serde(default, skip_serializing)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    #[serde(default)]
+    pub cert_file: Option<PathBuf>,
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_server_name: Option<String>,
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// Unavailable services are stored under `<kv_prefix>/<instance_id>/<service>`
+    #[serde(default = "default_kv_prefix")]
+    pub kv_prefix: String,
+    /// Identifies this consulsync in KV and in the session name, the hostname
+    /// when unset. Needed when instances share a hostname
+    pub instance_id: Option<String>,
+    /// TTL of the session holding the unavailable keys, renewed by the check loop
+    #[serde(default = "default_session_ttl", with = "humantime_serde")]
+    pub session_ttl: Duration,
 }
 impl Default for Consul {
     fn default() -> Self {
         Self {
             client: Client::new(),
             url: "http://localhost:8500".to_string(),
+            token: None,
+            token_file: None,
+            ca_file: None,
+            cert_file: None,
+            key_file: None,
+            tls_server_name: None,
+            insecure_skip_verify: false,
+            retry: RetryPolicy::default(),
+            kv_prefix: default_kv_prefix(),
+            instance_id: None,
+            session_ttl: default_session_ttl(),
         }
     }
 }
 
+// Written by hand so the config dump at startup does not leak the token
+impl fmt::Debug for Consul {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Consul")
+            .field("url", &self.url)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("token_file", &self.token_file)
+            .field("ca_file", &self.ca_file)
+            .field("cert_file", &self.cert_file)
+            .field("key_file", &self.key_file)
+            .field("tls_server_name", &self.tls_server_name)
+            .field("insecure_skip_verify", &self.insecure_skip_verify)
+            .field("retry", &self.retry)
+            .field("kv_prefix", &self.kv_prefix)
+            .field("instance_id", &self.instance_id)
+            .field("session_ttl", &self.session_ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+// Settings only, the client built from them is left out
+impl PartialEq for Consul {
+    fn eq(&self, other: &Self) -> bool {
+        self.url == other.url
+            && self.token == other.token
+            && self.token_file == other.token_file
+            && self.ca_file == other.ca_file
+            && self.cert_file == other.cert_file
+            && self.key_file == other.key_file
+            && self.tls_server_name == other.tls_server_name
+            && self.insecure_skip_verify == other.insecure_skip_verify
+            && self.retry == other.retry
+            && self.kv_prefix == other.kv_prefix
+            && self.instance_id == other.instance_id
+            && self.session_ttl == other.session_ttl
+    }
+}
+
+fn default_kv_prefix() -> String {
+    "consulsync".to_string()
+}
+
+fn default_session_ttl() -> Duration {
+    Duration::from_secs(30)
+}
+
 impl Consul {
-    pub fn new(url: &str) -> Self {
+    /// Build a client from the `[consul]` settings.
+    /// The token file is read on every call so a rotated token is picked up
+    /// the next time a client is built.
+    pub async fn connect(&self) -> Result<Self, ClientError> {
+        let token = self.token().await?;
         let mut headers = header::HeaderMap::new();
         headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json")); 
-        let client = Client::builder()
+        if let Some(token) = &token {
+            let mut value = header::HeaderValue::from_str(token)
+                .map_err(|e| ClientError::Config(format!("Invalid Consul token: {}", e)))?;
+            value.set_sensitive(true);
+            headers.insert("X-Consul-Token", value);
+        }
+        let mut url = self.url.clone();
+        let mut builder = Client::builder()
             .default_headers(headers)
-            .build()
-            .unwrap();
-        Consul {
+            .use_rustls_tls();
+        if let Some(ca_file) = &self.ca_file {
+            let ca = read_file(ca_file).await?;
+            builder = builder.add_root_certificate(Certificate::from_pem(&ca).map_err(tls_error)?);
+        }
+        match (&self.cert_file, &self.key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let mut identity = read_file(cert_file).await?;
+                identity.extend(read_file(key_file).await?);
+                builder = builder.identity(Identity::from_pem(&identity).map_err(tls_error)?);
+            },
+            (None, None) => (),
+            _ => return Err(ClientError::Config(
+                "Both cert_file and key_file must be set to use a client certificate".to_string(),
+            )),
+        }
+        if let Some(server_name) = &self.tls_server_name {
+            // Reqwest takes the SNI and verified name from the URL host, so the
+            // URL is rewritten to the server name which resolves to the agent address
+            let mut parsed = Url::parse(&self.url)
+                .map_err(|e| ClientError::Config(format!("Invalid Consul url {}: {}", self.url, e)))?;
+            let host = parsed.host_str().unwrap_or_default().trim_matches(['[', ']']);
+            let port = parsed.port_or_known_default().unwrap_or(8500);
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await
+                .map_err(|e| ClientError::Config(format!("Unable to resolve Consul url {}: {}", self.url, e)))?
+                .collect();
+            parsed.set_host(Some(server_name))
+                .map_err(|e| ClientError::Config(format!("Invalid tls_server_name {}: {}", server_name, e)))?;
+            builder = builder.resolve_to_addrs(server_name, &addrs);
+            url = parsed.as_str().trim_end_matches('/').to_string();
+        }
+        if self.insecure_skip_verify {
+            warn!("TLS certificate verification is disabled for Consul");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        let client = builder.build().map_err(tls_error)?;
+        Ok(Consul {
             client, 
-            url: url.to_string(),
+            url,
+            token,
+            ..self.clone()
+        })
+    }
+
+    /// Token to authenticate with, `token_file` taking precedence over `token`.
+    pub async fn token(&self) -> Result<Option<String>, ClientError> {
+        match &self.token_file {
+            Some(path) => {
+                let token = read_file(path).await?;
+                Ok(Some(String::from_utf8_lossy(&token).trim().to_string()))
+            },
+            None => Ok(self.token.clone()),
         }
     }
 
-    pub async fn _get_catalog_services(&self) -> Result<Service, ClientError> {
+    // Send a request, retrying transport and server errors according to the retry policy
+    async fn send(&self, request: RequestBuilder) -> Result<Response, ClientError> {
+        let mut attempt = 1;
+        loop {
+            let attempt_request = match request.try_clone() {
+                Some(attempt_request) => attempt_request,
+                None => return check_response(request.send().await?).await,
+            };
+            let result = match attempt_request.send().await {
+                Ok(response) => check_response(response).await,
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Err(e) if e.is_retryable() && attempt < self.retry.max_attempts => {
+                    let delay = self.retry.backoff(attempt);
+                    warn!("Consul request failed ({}), attempt {}/{}, retrying in {:?}", e, attempt, self.retry.max_attempts, delay);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    /// Service names known to the Consul servers with their tags. The agent
+    /// copies its services there in the background
+    pub async fn get_catalog_services(&self) -> Result<HashMap<String, Vec<String>>, ClientError> {
         let url = format!("{}/v1/catalog/services", self.url);
-        let response = self.client.get(&url).send().await?;
+        let response = self.send(self.client.get(&url)).await?;
         let body = response.text().await?;
         debug!("Body from catalog service {:?}", &body);
-        let services: Service = serde_json::from_str(&body)?;
+        let services: HashMap<String, Vec<String>> = serde_json::from_str(&body)?;
         Ok(services)
     }
     pub async fn get_agent_services(&self) -> Result<Vec<AgentService>, ClientError> {
         let url = format!("{}/v1/agent/services", self.url);
-        let response = self.client.get(&url).send().await?;
+        let response = self.send(self.client.get(&url)).await?;
         let body = response.text().await?;
         debug!("Body from agent service {:?}", &body);
         let services: HashMap<String, AgentService> = serde_json::from_str(&body)?;
-        Ok(services.into_iter().map(|(_, v)| v).collect())
+        Ok(services.into_values().collect())
     }
-    pub async fn register_agent_service(&self, service: &RegisterAgentService) -> Result<(), ClientError> {
-        let url = format!("{}/v1/agent/service/register", self.url);
+    /// The service as registered, with the tag and meta keys consulsync uses
+    /// to recognise its services
+    pub fn registration(&self, service: &RegisterAgentService) -> RegisterAgentService {
         let mut service = service.clone();
         service.tags.push("nixconsul".to_string());
-        let body = serde_json::to_string(&service)?;
-        let response = self.client.put(&url).body(body).send().await?;
+        service.meta.insert(INSTANCE_META.to_string(), self.instance_id());
+        service.meta.insert(CHECK_META.to_string(), check_hash(&service.check));
+        service
+    }
+
+    pub async fn register_agent_service(&self, service: &RegisterAgentService) -> Result<(), ClientError> {
+        let url = format!("{}/v1/agent/service/register", self.url);
+        let body = serde_json::to_string(&self.registration(service))?;
+        let response = self.send(self.client.put(&url).body(body)).await?;
         debug!("Response from agent service registration {:?}", &response);
-        let status = response.status();
-        match status {
-            reqwest::StatusCode::OK => {
-                info!("Service registration successful");
-                Ok(())
-            },
-            _ => Err(ClientError {
-                message: format!("Service registration failed with status: {}", status),
-            }),
-        }
+        info!("Service registration successful");
+        Ok(())
     }
 
     pub async fn deregister_agent_service(&self, service_id: &str) -> Result<(), ClientError> {
         let url = format!("{}/v1/agent/service/deregister/{}", self.url, service_id);
-        let response = self.client.put(&url).send().await?;
-        debug!("Response from agent service deregistration {:?}", &response);
-        let status = response.status();
-        match status {
-            reqwest::StatusCode::OK => {
+        match self.send(self.client.put(&url)).await {
+            Ok(response) => {
+                debug!("Response from agent service deregistration {:?}", &response);
                 info!("Service deregistration successful");
                 Ok(())
             },
-            reqwest::StatusCode::NOT_FOUND => {
+            Err(ClientError::NotFound(_)) => {
                 info!("Service {} not found", service_id);
                 Ok(())
             },
-            _ => Err(ClientError {
-                message: format!("Service deregistration failed with status: {}", status),
-            }),
+            Err(e) => Err(e),
         }
     }
 
+    pub async fn get_agent_checks(&self) -> Result<Vec<AgentCheck>, ClientError> {
+        let url = format!("{}/v1/agent/checks", self.url);
+        let response = self.send(self.client.get(&url)).await?;
+        let body = response.text().await?;
+        debug!("Body from agent checks {:?}", &body);
+        let checks: HashMap<String, AgentCheck> = serde_json::from_str(&body)?;
+        Ok(checks.into_values().collect())
+    }
+
+    /// Push a probe result to a TTL check through `/v1/agent/check/pass|warn|fail`
+    pub async fn update_ttl_check(&self, check_id: &str, result: &ProbeResult) -> Result<(), ClientError> {
+        let status = match result.health {
+            Health::Passing => "pass",
+            Health::Warning => "warn",
+            Health::Critical => "fail",
+        };
+        let url = format!("{}/v1/agent/check/{}/{}", self.url, status, check_id);
+        let request = self.client.put(&url).query(&[("note", &result.output)]);
+        let response = self.send(request).await?;
+        debug!("Response from ttl check update {:?}", &response);
+        Ok(())
+    }
+
+    /// Write a key, acquiring it with the session when one is given.
+    /// Returns false when the key is locked by another session
+    pub async fn put_key(&self, key: &str, value: Vec<u8>, session: Option<&str>) -> Result<bool, ClientError> {
+        let url = format!("{}/v1/kv/{}", self.url, key);
+        let mut request = self.client.put(&url).body(value);
+        if let Some(session) = session {
+            request = request.query(&[("acquire", session)]);
+        }
+        let response = self.send(request).await?;
+        debug!("Response from kv put {:?}", &response);
+        Ok(response.text().await?.trim() == "true")
+    }
+
+    pub async fn delete_key(&self, key: &str) -> Result<(), ClientError> {
+        let url = format!("{}/v1/kv/{}", self.url, key);
+        let response = self.send(self.client.delete(&url)).await?;
+        debug!("Response from kv delete {:?}", &response);
+        Ok(())
+    }
+
+    pub async fn delete_prefix(&self, prefix: &str) -> Result<(), ClientError> {
+        let url = format!("{}/v1/kv/{}?recurse", self.url, prefix);
+        let response = self.send(self.client.delete(&url)).await?;
+        debug!("Response from kv recursive delete {:?}", &response);
+        Ok(())
+    }
+
+    pub fn instance_id(&self) -> String {
+        self.instance_id.clone().unwrap_or_else(hostname)
+    }
+
+    // Where the unavailable services of this instance are stored, with a trailing slash
+    fn state_prefix(&self) -> String {
+        format!("{}/{}/", self.kv_prefix.trim_matches('/'), self.instance_id())
+    }
+
+    /// Create the session holding the unavailable keys. It is tied to the node
+    /// health and deleted with its keys when it is not renewed within the TTL
+    pub async fn create_session(&self) -> Result<String, ClientError> {
+        let url = format!("{}/v1/session/create", self.url);
+        let session = SessionRequest {
+            name: session_name(&self.instance_id()),
+            ttl: go_duration(self.session_ttl),
+            behavior: "delete".to_string(),
+            node_checks: vec!["serfHealth".to_string()],
+            // A restarted consulsync takes the keys over right away
+            lock_delay: "0s".to_string(),
+        };
+        let body = serde_json::to_string(&session)?;
+        let response = self.send(self.client.put(&url).body(body)).await?;
+        let body = response.text().await?;
+        debug!("Body from session create {:?}", &body);
+        let session: SessionResponse = serde_json::from_str(&body)?;
+        Ok(session.id)
+    }
+
+    /// Renew a session, `ClientError::NotFound` once it has expired
+    pub async fn renew_session(&self, id: &str) -> Result<(), ClientError> {
+        let url = format!("{}/v1/session/renew/{}", self.url, id);
+        let response = self.send(self.client.put(&url)).await?;
+        debug!("Response from session renew {:?}", &response);
+        Ok(())
+    }
+
+    pub async fn destroy_session(&self, id: &str) -> Result<(), ClientError> {
+        let url = format!("{}/v1/session/destroy/{}", self.url, id);
+        let response = self.send(self.client.put(&url)).await?;
+        debug!("Response from session destroy {:?}", &response);
+        Ok(())
+    }
+
+    /// IDs of the sessions created by this instance, or by every consulsync
+    /// instance with `all_instances`
+    pub async fn get_sessions(&self, all_instances: bool) -> Result<Vec<String>, ClientError> {
+        let url = format!("{}/v1/session/list", self.url);
+        let response = self.send(self.client.get(&url)).await?;
+        let body = response.text().await?;
+        debug!("Body from session list {:?}", &body);
+        let sessions: Vec<SessionEntry> = serde_json::from_str(&body)?;
+        let name = session_name(&self.instance_id());
+        Ok(sessions.into_iter()
+            .filter(|session| if all_instances { session.name.starts_with(SESSION_PREFIX) } else { session.name == name })
+            .map(|session| session.id)
+            .collect())
+    }
+
+    /// Keys and decoded values under a prefix, empty when nothing is stored
+    pub async fn get_values(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>, ClientError> {
+        let url = format!("{}/v1/kv/{}?recurse", self.url, prefix);
+        let pairs: Vec<KvPair> = match self.send(self.client.get(&url)).await {
+            Ok(response) => {
+                let body = response.text().await?;
+                debug!("Body from kv recurse {:?}", &body);
+                serde_json::from_str(&body)?
+            },
+            Err(ClientError::NotFound(_)) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        pairs.into_iter().map(|pair| {
+            let value = BASE64.decode(pair.value.unwrap_or_default())
+                .map_err(|e| ClientError::Config(format!("invalid value for key {}: {}", pair.key, e)))?;
+            Ok((pair.key, value))
+        }).collect()
+    }
+
+    /// Services registered by this instance. Services registered before the
+    /// instance meta key existed count as this instance's
     pub async fn get_managed_services(&self) -> Result<Vec<AgentService>, ClientError> {
+        let instance_id = self.instance_id();
+        let services = self.get_tagged_services().await?;
+        Ok(services.into_iter()
+            .filter(|service| service.meta.get(INSTANCE_META).is_none_or(|instance| *instance == instance_id))
+            .collect())
+    }
+
+    /// Every service tagged `nixconsul`, whichever consulsync instance registered it
+    pub async fn get_tagged_services(&self) -> Result<Vec<AgentService>, ClientError> {
         let services = self.get_agent_services().await;
         match services {
             Ok(services) => {
@@ -256,4 +972,304 @@ impl Consul {
         }
     }
 
+    pub async fn register_unavailable_service(
+        &self,
+        check: &ExternalCheck,
+        result: &ProbeResult,
+        since: SystemTime,
+        failures: u32,
+        session: Option<&str>,
+    ) -> Result<(), ClientError> {
+        let key = format!("{}{}", self.state_prefix(), check.id);
+        let record = UnavailableService {
+            id: check.id.clone(),
+            since,
+            last_error: if result.output.is_empty() {
+                "unavailable".to_string()
+            } else {
+                result.output.clone()
+            },
+            failures,
+            address: check.address.clone(),
+            port: check.port,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        };
+        // A failed write is returned so the caller keeps the service registered and retries
+        if !self.put_key(&key, serde_json::to_vec(&record)?, session).await? {
+            warn!("Key {} is held by another session, it is released once that session expires", key);
+        }
+        Ok(())
+    }
+
+    pub async fn deregister_unavailable_service(&self, check: &ExternalCheck) -> Result<(), ClientError> {
+        let key = format!("{}{}", self.state_prefix(), check.id);
+        self.delete_key(&key).await
+    }
+
+    pub async fn clear_unavailable_services(&self) -> Result<(), ClientError> {
+        let key = self.state_prefix();
+        self.delete_prefix(&key).await
+    }
+
+    /// Clear the unavailable services of every instance under `kv_prefix`
+    pub async fn clear_all_unavailable_services(&self) -> Result<(), ClientError> {
+        let key = format!("{}/", self.kv_prefix.trim_matches('/'));
+        self.delete_prefix(&key).await
+    }
+
+    pub async fn get_unavailable_services(&self) -> Result<Vec<UnavailableService>, ClientError> {
+        let key = self.state_prefix();
+        let mut unavailable_services = Vec::new();
+        for (key, value) in self.get_values(&key).await? {
+            let id = key.rsplit('/').next().unwrap_or_default().to_string();
+            let record = match serde_json::from_slice::<UnavailableService>(&value) {
+                Ok(record) => UnavailableService { id, ..record },
+                // Written by an older consulsync as the bare probe output, which
+                // did not record when the service went down
+                Err(_) => UnavailableService {
+                    last_error: String::from_utf8_lossy(&value).to_string(),
+                    since: SystemTime::now(),
+                    failures: 0,
+                    address: String::new(),
+                    port: 0,
+                    version: String::new(),
+                    id,
+                },
+            };
+            unavailable_services.push(record);
+        }
+        info!("Unavailable services: {:?}", unavailable_services.iter().map(|s| &s.id).collect::<Vec<_>>());
+        Ok(unavailable_services)
+    }
+
+}
+
+pub fn hostname() -> String {
+    match gethostname().into_string() {
+        Ok(h) => h,
+        Err(_) => "unknown".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use std::fs;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use reqwest_rustls::{AlertDescription, CertificateError, Error};
+    use rustls::pki_types::PrivateKeyDer;
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{RootCertStore, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    struct Pki {
+        dir: PathBuf,
+        server_config: Arc<ServerConfig>,
+    }
+
+    // Generate a CA, a server certificate for consul.test and a client certificate,
+    // all written to a temporary directory as PEM files
+    fn pki(name: &str) -> Pki {
+        let dir = std::env::temp_dir().join(format!("consulsync-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server = CertificateParams::new(vec!["consul.test".to_string()]).unwrap()
+            .signed_by(&server_key, &ca, &ca_key).unwrap();
+        let client_key = KeyPair::generate().unwrap();
+        let client = CertificateParams::new(vec!["consulsync".to_string()]).unwrap()
+            .signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        fs::write(dir.join("client.pem"), client.pem()).unwrap();
+        fs::write(dir.join("client-key.pem"), client_key.serialize_pem()).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build().unwrap();
+        let server_config = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                vec![server.der().clone()],
+                PrivateKeyDer::Pkcs8(server_key.serialize_der().into()),
+            )
+            .unwrap();
+        Pki { dir, server_config: Arc::new(server_config) }
+    }
+
+    // Minimal HTTPS agent answering every request with an empty service list
+    async fn serve(server_config: Arc<ServerConfig>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(server_config);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let response = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}";
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        addr
+    }
+
+    // The rustls error behind a failed request, so the tests can tell a
+    // rejected handshake from any other connection failure
+    fn tls_failure(e: &ClientError) -> Option<&Error> {
+        let mut source = std::error::Error::source(e);
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<Error>() {
+                return Some(e);
+            }
+            source = match e.downcast_ref::<std::io::Error>() {
+                Some(e) => e.get_ref().map(|e| e as &(dyn std::error::Error + 'static)),
+                None => e.source(),
+            };
+        }
+        None
+    }
+
+    fn settings(addr: SocketAddr, pki: &Pki) -> Consul {
+        Consul {
+            url: format!("https://127.0.0.1:{}", addr.port()),
+            ca_file: Some(pki.dir.join("ca.pem")),
+            cert_file: Some(pki.dir.join("client.pem")),
+            key_file: Some(pki.dir.join("client-key.pem")),
+            tls_server_name: Some("consul.test".to_string()),
+            retry: RetryPolicy { max_attempts: 1, ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    fn policy(jitter: bool) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_delay() {
+        let policy = policy(false);
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(2), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(2));
+        assert_eq!(policy.backoff(7), Duration::from_secs(30));
+        // The exponent is clamped, a long outage does not overflow
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn backoff_jitter_stays_between_half_and_full_delay() {
+        for attempt in 1..10 {
+            let full = policy(false).backoff(attempt);
+            let delay = policy(true).backoff(attempt);
+            assert!(delay >= full / 2 && delay <= full, "attempt {}: {:?} not within {:?}", attempt, delay, full);
+        }
+    }
+
+    #[test]
+    fn backoff_without_base_delay_is_zero() {
+        let policy = RetryPolicy { base_delay: Duration::ZERO, ..policy(true) };
+        assert_eq!(policy.backoff(3), Duration::ZERO);
+    }
+
+    #[test]
+    fn grpc_check_carries_the_tls_settings() {
+        let probe = GrpcProbe { service: String::new(), tls: true, ca_file: None, tls_server_name: None, tls_skip_verify: false };
+        let check = serde_json::to_value(ServiceCheck::grpc("10.0.0.1:50051", &probe)).unwrap();
+        assert_eq!(check["GRPCUseTLS"], true);
+        assert!(check.get("TLSServerName").is_none() && check.get("TLSSkipVerify").is_none());
+        let probe = GrpcProbe { tls_server_name: Some("grpc.test".to_string()), tls_skip_verify: true, ..probe };
+        let check = serde_json::to_value(ServiceCheck::grpc("10.0.0.1:50051", &probe)).unwrap();
+        assert_eq!(check["TLSServerName"], "grpc.test");
+        assert_eq!(check["TLSSkipVerify"], true);
+    }
+
+    #[tokio::test]
+    async fn mtls_with_server_name() {
+        let pki = pki("mtls");
+        let addr = serve(pki.server_config.clone()).await;
+        let client = settings(addr, &pki).connect().await.unwrap();
+        let services = client.get_agent_services().await.unwrap();
+        assert!(services.is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_client_certificate_is_rejected() {
+        let pki = pki("noclient");
+        let addr = serve(pki.server_config.clone()).await;
+        let client = Consul {
+            cert_file: None,
+            key_file: None,
+            ..settings(addr, &pki)
+        }.connect().await.unwrap();
+        let e = client.get_agent_services().await.unwrap_err();
+        assert!(matches!(
+            tls_failure(&e),
+            Some(Error::AlertReceived(AlertDescription::CertificateRequired))
+        ), "{:?}", e);
+    }
+
+    #[tokio::test]
+    async fn server_name_mismatch_is_rejected() {
+        let pki = pki("mismatch");
+        let addr = serve(pki.server_config.clone()).await;
+        let client = Consul {
+            tls_server_name: None,
+            ..settings(addr, &pki)
+        }.connect().await.unwrap();
+        let e = client.get_agent_services().await.unwrap_err();
+        assert!(matches!(
+            tls_failure(&e),
+            Some(Error::InvalidCertificate(CertificateError::NotValidForName))
+        ), "{:?}", e);
+    }
+
+    #[tokio::test]
+    async fn insecure_skips_verification() {
+        let pki = pki("insecure");
+        let addr = serve(pki.server_config.clone()).await;
+        let client = Consul {
+            ca_file: None,
+            tls_server_name: None,
+            insecure_skip_verify: true,
+            ..settings(addr, &pki)
+        }.connect().await.unwrap();
+        assert!(client.get_agent_services().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn client_certificate_requires_key() {
+        let pki = pki("nokey");
+        let settings = Consul {
+            key_file: None,
+            ..settings("127.0.0.1:8501".parse().unwrap(), &pki)
+        };
+        assert!(settings.connect().await.is_err());
+    }
 }
@@ -1,168 +1,675 @@
 use tracing::{info, Level,debug,error,warn};
-use tracing_subscriber;
-use std::sync::mpsc;
-use std::{time::Duration, thread};
-use notify::{PollWatcher, RecursiveMode, Watcher, Config as NotifyConfig};
-use clap::{arg, command, Parser};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use tokio::time::MissedTickBehavior;
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinSet;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher, Config as NotifyConfig};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap::error::ErrorKind;
+use std::path::{Path, PathBuf};
 use tokio::task;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::signal::unix::{signal, SignalKind};
 
 mod consul;
 mod config;
 mod check;
+mod plan;
+mod template;
 
-use consul::RegisterAgentService;
-use crate::check::{RsConsulExt, ExternalCheck};
+use consul::{ttl_check_id, ClientError, Consul, UnavailableService};
+use crate::check::{CheckState, ExternalCheck, Health, ProbeResult, Transition};
+use crate::plan::Change;
 
 //const CONFIG_FILE: &str = "config.toml";
+const CONFIG_DEBOUNCE: Duration = Duration::from_millis(300);
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(short, long)]
-    config: PathBuf,
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-async fn config_services(config: config::Config) -> anyhow::Result<()> {
-    let client = consul::Consul::new(&config.consul.url);
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the daemon keeping Consul in sync with the config (default)
+    Run,
+    /// Run a single sync pass and exit
+    Apply,
+    /// Parse and merge the config, then exit
+    Validate,
+    /// Print the changes a sync would make to Consul without applying them,
+    /// exits with status 2 when there is drift
+    Plan,
+    /// Show the managed services with their health and unavailable state
+    List,
+    /// Deregister every service managed by this consulsync instance and
+    /// clear its unavailable state
+    Purge {
+        /// Purge every service tagged nixconsul, whichever instance registered it
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+#[derive(Debug, Default)]
+struct ReconcileReport {
+    registered: Vec<String>,
+    updated: Vec<String>,
+    deregistered: Vec<String>,
+}
+impl ReconcileReport {
+    fn is_empty(&self) -> bool {
+        self.registered.is_empty() && self.updated.is_empty() && self.deregistered.is_empty()
+    }
+}
+impl std::fmt::Display for ReconcileReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "registered {:?}, updated {:?}, deregistered {:?}", self.registered, self.updated, self.deregistered)
+    }
+}
+
+async fn config_services(config: config::Config) -> anyhow::Result<ReconcileReport> {
+    let client = config.consul.connect().await?;
     let managed_services = client.get_managed_services().await?;
-    let mut uptodate: Vec<String> = Vec::new();
-    for service in managed_services {
-        if config.services.iter().any(|s| s == &service) {
-            uptodate.push(service.id);
-        } else {
-            info!("Service {} is not in config deleting it...", service.id);
-            client.deregister_agent_service(&service.id).await?;
+    // Services pulled by the check loop stay deregistered until they are available again,
+    // so the sync fails rather than register them all when the records cannot be read
+    let unavailable_services = client.get_unavailable_services().await?;
+    let mut report = ReconcileReport::default();
+    for change in plan::plan(&config.services, &managed_services, &unavailable_services) {
+        match change {
+            Change::Deregister(id) => {
+                info!("Service {} is not in config deleting it...", id);
+                match client.deregister_agent_service(&id).await {
+                    Ok(_) => report.deregistered.push(id),
+                    Err(e) => skip_unless_retryable(&id, e)?,
+                }
+            },
+            Change::Register(service) => {
+                info!("Registering service {}", service.id());
+                let name = service.id().to_string();
+                match client.register_agent_service(&service.into()).await {
+                    Ok(_) => report.registered.push(name),
+                    Err(e) => skip_unless_retryable(&name, e)?,
+                }
+            },
+            Change::Update(service, diff) => {
+                // Registering again with the same ID replaces the service in place
+                info!("Updating service {}: {}", service.id(), diff.join(", "));
+                let name = service.id().to_string();
+                match client.register_agent_service(&service.into()).await {
+                    Ok(_) => report.updated.push(name),
+                    Err(e) => skip_unless_retryable(&name, e)?,
+                }
+            },
         }
     }
-    for service in config.services {
-        if uptodate.contains(&service.name) {
-            continue;
-        }
-        info!("Registering service {}", service.name);
-        client.register_agent_service(&service.into()).await?;
+    Ok(report)
+}
+
+// Print the pending changes, returns whether Consul has drifted from the config
+async fn plan_services(config: config::Config) -> anyhow::Result<bool> {
+    let client = config.consul.connect().await?;
+    let managed_services = client.get_managed_services().await?;
+    let unavailable_services = client.get_unavailable_services().await?;
+    let changes = plan::plan(&config.services, &managed_services, &unavailable_services);
+    if changes.is_empty() {
+        println!("No changes, services are in sync with Consul");
+        return Ok(false);
+    }
+    for change in &changes {
+        print!("{}", change);
+    }
+    println!("{} change(s) to apply", changes.len());
+    Ok(true)
+}
+
+// ACL and validation errors won't go away by trying again, so they are logged
+// and the remaining services are still synced
+fn skip_unless_retryable(service: &str, e: ClientError) -> Result<(), ClientError> {
+    if e.is_retryable() {
+        return Err(e);
+    }
+    match &e {
+        ClientError::AclDenied(_) => error!("Permission denied syncing service {}, check the ACL token: {}", service, e),
+        ClientError::Status { status, .. } if *status == reqwest::StatusCode::BAD_REQUEST => {
+            error!("Consul rejected service {}: {}", service, e)
+        },
+        _ => error!("Error syncing service {}: {}", service, e),
     }
     Ok(())
 }
 
-async fn check_services(config: config::Config, sender: mpsc::Sender<()>) -> anyhow::Result<()> {
-    let client = consul::Consul::new(&config.consul.url);
-    let rs_client: rs_consul::Consul = consul::Consul::new(&config.consul.url).into();
-    let unavailable_services = match rs_client.get_unavailable_services().await {
-        Ok(services) => services,
-        Err(_) => {
-            info!("It seems that there is no existing unavailable services");
-            Vec::new()
-        }
-    };
-    for service in &config.services {
-        let register_service: RegisterAgentService = service.clone().into();
-        let service_check: ExternalCheck = register_service.into(); 
-        if !service_check.service_available().await {
-            warn!("Service {} is not available", service.name);
-            match rs_client.register_unavailable_service(&service_check).await {
+// Act on a probe result once the rise/fall thresholds are applied
+async fn check_service(
+    client: &Consul,
+    service: &config::ServiceConfig,
+    service_check: &ExternalCheck,
+    state: &mut CheckState,
+    result: ProbeResult,
+    session: Option<&str>,
+    sender: &UnboundedSender<()>,
+) -> anyhow::Result<()> {
+    let transition = state.observe(result.health, service.check().rise, service.check().fall);
+    if service.check().ttl.is_some() {
+        // Consul holds the health, the service stays registered either way
+        let health = state.health(result.health);
+        debug!("Service {} is {:?}", service.id(), health);
+        let result = ProbeResult { health, ..result };
+        client.update_ttl_check(&ttl_check_id(service.id()), &result).await?;
+        return Ok(());
+    }
+    if result.health == Health::Warning {
+        warn!("Service {} is in warning state: {}", service.id(), result.output);
+    }
+    match transition {
+        Some(Transition::Down) => {
+            warn!("Service {} is not available: {}", service.id(), result.output);
+            match client.register_unavailable_service(service_check, &result, state.since(), state.failures(), session).await {
                 Ok(_) => {
                     info!("Service registered as unavailable");
-                    warn!("Deregistering service {} since unavailable", service.name);
-                    client.deregister_agent_service(&service.name).await?;
+                    warn!("Deregistering service {} since unavailable", service.id());
+                    if let Err(e) = client.deregister_agent_service(service.id()).await {
+                        // Still registered, so go through the Down transition again on the next probe
+                        state.up = true;
+                        return Err(e.into());
+                    }
                 },
                 Err(e) => {
                     warn!("Error registering service as unavailable: {:?}", e);
+                    // Try again on the next probe
+                    state.up = true;
                 }
             }
-        } else {
-            debug!("Service {} is available", service.name);
-            sender.send(()).unwrap();
-            if unavailable_services.contains(&service.name) {
-                match rs_client.deregister_unavailable_service(&service_check).await {
-                    Ok(_) => {
-                        info!("Service {} was unavailable, now available", service.name);
-                    },
-                    Err(e) => {
-                        warn!("Error deregistering service as available: {:?}", e);
-                    }
+        },
+        Some(Transition::Up) => {
+            match client.deregister_unavailable_service(service_check).await {
+                Ok(_) => {
+                    info!("Service {} was unavailable, now available", service.id());
+                    // Trigger a sync so the service gets registered again
+                    let _ = sender.send(());
+                },
+                Err(e) => {
+                    warn!("Error deregistering service as available: {:?}", e);
+                    state.up = false;
                 }
             }
+        },
+        None if !state.up && result.health == Health::Critical => {
+            debug!("Service {} is still unavailable", service.id());
+            // Keep the error and failure count in the record up to date
+            client.register_unavailable_service(service_check, &result, state.since(), state.failures(), session).await?;
+        },
+        None if result.health == Health::Critical => {
+            warn!("Service {} failed its check ({}/{}): {}", service.id(), state.failures(), service.check().fall, result.output);
+        },
+        None if state.up => debug!("Service {} is available", service.id()),
+        None => debug!("Service {} is recovering ({}/{})", service.id(), state.successes(), service.check().rise),
+    }
+    Ok(())
+}
+
+// Probe a single service on its own interval, the semaphore bounds how many
+// probes run at the same time across all services
+#[allow(clippy::too_many_arguments)]
+async fn loop_check_service(
+    client: Consul,
+    service: config::ServiceConfig,
+    unavailable: Option<UnavailableService>,
+    states: Arc<Mutex<HashMap<String, CheckState>>>,
+    semaphore: Arc<Semaphore>,
+    session: watch::Receiver<Option<String>>,
+    sender: UnboundedSender<()>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let service_check: ExternalCheck = service.clone().into();
+    let ttl = service.check().ttl.is_some();
+    // Left over from before the service switched to a TTL check
+    if ttl && unavailable.is_some() {
+        match client.deregister_unavailable_service(&service_check).await {
+            Ok(_) => {
+                let _ = sender.send(());
+            },
+            Err(e) => warn!("Error deregistering service as available: {:?}", e),
+        }
+    }
+    let mut state = states.lock().unwrap().get(service.id()).cloned()
+        .unwrap_or_else(|| match &unavailable {
+            Some(record) if !ttl => CheckState::unavailable(record),
+            _ => CheckState::new(true),
+        });
+    let mut interval = tokio::time::interval(service.check().interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        // A probe that started is acted on, so no key is written without the
+        // deregistration that goes with it
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = stop.changed() => return,
+        }
+        let result = {
+            let Ok(_permit) = semaphore.acquire().await else {
+                return;
+            };
+            service_check.probe().await
+        };
+        let session = session.borrow().clone();
+        if let Err(e) = check_service(&client, &service, &service_check, &mut state, result, session.as_deref(), &sender).await {
+            error!("Error checking service {}: {}", service.id(), e);
+        }
+        states.lock().unwrap().insert(service.id().to_string(), state.clone());
+    }
+}
+
+// Renew the session holding the unavailable keys, or create it when there is
+// none yet or it expired. The checks still failing acquire their keys again
+// with the new one
+async fn renew_session(client: &Consul, session: &watch::Sender<Option<String>>) {
+    let current = session.borrow().clone();
+    let renewed = match &current {
+        Some(id) => client.renew_session(id).await,
+        None => Err(ClientError::NotFound("no session".to_string())),
+    };
+    match renewed {
+        Ok(_) => debug!("Renewed session {}", current.unwrap_or_default()),
+        Err(ClientError::NotFound(_)) => match client.create_session().await {
+            Ok(id) => {
+                info!("Created session {}", id);
+                session.send_replace(Some(id));
+            },
+            Err(e) => error!("Error creating session: {}", e),
+        },
+        Err(e) => warn!("Error renewing session: {}", e),
+    }
+}
+
+async fn keep_session(client: Consul, session: watch::Sender<Option<String>>, mut stop: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(client.session_ttl / 2) => (),
+            _ = stop.changed() => return,
         }
+        renew_session(&client, &session).await;
     }
+}
 
+// Spawn a check task per configured service
+async fn schedule_checks(
+    config: &config::Config,
+    states: &Arc<Mutex<HashMap<String, CheckState>>>,
+    session: &watch::Sender<Option<String>>,
+    sender: &UnboundedSender<()>,
+    stop: &watch::Receiver<bool>,
+    checks: &mut JoinSet<()>,
+) -> anyhow::Result<()> {
+    let client = config.consul.connect().await?;
+    let unavailable_services = match client.get_unavailable_services().await {
+        Ok(services) => services,
+        Err(_) => {
+            info!("It seems that there is no existing unavailable services");
+            Vec::new()
+        }
+    };
+    // Before the first probe so no unavailable key is written without the session
+    renew_session(&client, session).await;
+    let semaphore = Arc::new(Semaphore::new(config.max_concurrent_checks.max(1)));
+    for service in &config.services {
+        checks.spawn(loop_check_service(
+            client.clone(),
+            service.clone(),
+            unavailable_services.iter().find(|s| s.id == service.id()).cloned(),
+            states.clone(),
+            semaphore.clone(),
+            session.subscribe(),
+            sender.clone(),
+            stop.clone(),
+        ));
+    }
+    checks.spawn(keep_session(client, session.clone(), stop.clone()));
     Ok(())
 }
- 
 
+// Watch the parent directories rather than the files themselves, editors and
+// secret managers usually replace a file instead of writing it in place
 fn watch_config_file(
-    file_path: &std::path::Path,
-    sender: mpsc::Sender<()>,
-) -> anyhow::Result<()> {
-    let (file_tx, file_rx) = mpsc::channel();
-    let mut watcher = PollWatcher::new(file_tx, NotifyConfig::default().with_manual_polling()).unwrap();
-    watcher.watch(file_path.as_ref(), RecursiveMode::Recursive).unwrap();
-
-    std::thread::spawn(move || {
-        for res in file_rx {
-            match res {
-                Ok(event) => {
+    file_paths: &[PathBuf],
+    sender: UnboundedSender<()>,
+) -> anyhow::Result<RecommendedWatcher> {
+    let mut files = Vec::new();
+    let mut directories = Vec::new();
+    for file_path in file_paths {
+        let directory = match file_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.canonicalize()?,
+            _ => std::env::current_dir()?,
+        };
+        if let Some(name) = file_path.file_name() {
+            files.push(directory.join(name));
+        }
+        if !directories.contains(&directory) {
+            directories.push(directory);
+        }
+    }
+    let mut watcher = RecommendedWatcher::new(move |res: notify::Result<Event>| {
+        match res {
+            Ok(event) => {
+                if !(event.kind.is_create() || event.kind.is_modify() || event.kind.is_remove()) {
+                    return;
+                }
+                if event.paths.iter().any(|path| files.contains(path)) {
                     debug!("changed: {:?}", event);
-                    sender.send(()).unwrap();
-                },
-                Err(e) => error!("watch error: {:?}", e),
-            }
+                    let _ = sender.send(());
+                }
+            },
+            Err(e) => error!("watch error: {:?}", e),
         }
-    });
-    loop {
-        watcher.poll().unwrap();
-        thread::sleep(Duration::from_secs(5));
+    }, NotifyConfig::default())?;
+    for directory in &directories {
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
     }
+    Ok(watcher)
 }
 
-async fn loop_check_services(config: config::Config, sender: mpsc::Sender<()>) {
+// Wait for the check tasks to act on the probes in flight once `stop` is sent
+async fn stop_checks(stop: watch::Sender<bool>, checks: &mut JoinSet<()>) {
+    stop.send_replace(true);
+    while checks.join_next().await.is_some() {}
+}
+
+async fn loop_check_services(
+    mut config_rx: watch::Receiver<config::Config>,
+    session: watch::Sender<Option<String>>,
+    sender: UnboundedSender<()>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    // Kept across reloads so a reload does not reset the thresholds
+    let states = Arc::new(Mutex::new(HashMap::new()));
     loop {
-        debug!("Checking services...");
-        match check_services(config.clone(), sender.clone()).await {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Error checking service: {}", e);
-            }
+        let config = config_rx.borrow_and_update().clone();
+        let mut checks = JoinSet::new();
+        let (stop, stop_rx) = watch::channel(false);
+        debug!("Scheduling checks for {} services", config.services.len());
+        if let Err(e) = schedule_checks(&config, &states, &session, &sender, &stop_rx, &mut checks).await {
+            error!("Error scheduling checks: {}", e);
         }
-        tokio::time::sleep(Duration::from_secs(10)).await;
-        debug!("Checking services...end");
+        let changed = tokio::select! {
+            changed = config_rx.changed() => changed.is_ok(),
+            _ = shutdown.changed() => false,
+        };
+        stop_checks(stop, &mut checks).await;
+        if !changed {
+            return;
+        }
+        debug!("Config changed, rescheduling checks");
+        let ids: Vec<String> = config_rx.borrow().services.iter().map(|s| s.id().to_string()).collect();
+        states.lock().unwrap().retain(|id, _| ids.contains(id));
     }
 }
 
-async fn loop_config_services(mut config: config::Config,config_file: PathBuf, file_rx: mpsc::Receiver<()>) {
+// The config file, the kind files and the token file
+fn watch_files(
+    config_file: &Path,
+    config: &config::Config,
+    sender: UnboundedSender<()>,
+) -> (Vec<PathBuf>, Option<RecommendedWatcher>) {
+    let mut files = vec![config_file.to_path_buf()];
+    files.extend(config.watched_files());
+    let watcher = match watch_config_file(&files, sender) {
+        Ok(watcher) => Some(watcher),
+        Err(err) => {
+            error!("Error monitoring config file changes: {}", err);
+            None
+        }
+    };
+    (files, watcher)
+}
+
+// What woke the config loop up
+enum Trigger {
+    /// A watched file changed
+    File,
+    /// The check loop saw a service come back
+    Sync,
+    /// The reconcile interval or retry delay elapsed
+    Tick,
+    Closed,
+}
+
+async fn loop_config_services(
+    mut config: config::Config,
+    config_file: PathBuf,
+    file_tx: UnboundedSender<()>,
+    mut file_rx: UnboundedReceiver<()>,
+    mut sync_rx: UnboundedReceiver<()>,
+    config_tx: watch::Sender<config::Config>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let (mut watched_files, mut _watcher) = watch_files(&config_file, &config, file_tx.clone());
+    // Read when a client is built, so a rotated token needs new check clients
+    // even though the config is the same
+    let mut token = config.consul.token().await.ok().flatten();
+    // Number of consecutive failed syncs, a failed sync is retried with backoff
+    // instead of waiting for the next reconcile tick
+    let mut failures: u32 = 0;
     loop {
-        match file_rx.recv() {
-            Ok(_) => {
+        let timeout = if failures == 0 {
+            config.reconcile_interval
+        } else {
+            let delay = config.consul.retry.backoff(failures);
+            warn!("Sync failed {} time(s), retrying in {:?}", failures, delay);
+            delay
+        };
+        // Only stopped between syncs so a sync is never left half done
+        let trigger = tokio::select! {
+            event = tokio::time::timeout(timeout, file_rx.recv()) => match event {
+                Ok(Some(_)) => Trigger::File,
+                Ok(None) => Trigger::Closed,
+                Err(_) => Trigger::Tick,
+            },
+            Some(_) = sync_rx.recv() => Trigger::Sync,
+            _ = shutdown.changed() => return,
+        };
+        match trigger {
+            Trigger::File => {
+                // A single save usually comes as a burst of events, wait for it to settle
+                while let Ok(Some(_)) = tokio::time::timeout(CONFIG_DEBOUNCE, file_rx.recv()).await {}
                 debug!("Config file changed, syncing...");
-                thread::sleep(Duration::from_secs(1));
-                let new_config = match config::read(&config_file) {
-                    Ok(config) => config,
+                match config::read(&config_file) {
+                    Ok(new_config) => {
+                        let new_token = new_config.consul.token().await.ok().flatten();
+                        let changed = new_config != config || new_token != token;
+                        config = new_config;
+                        token = new_token;
+                        // A kind file may have been added or renamed
+                        if config.watched_files() != watched_files[1..] {
+                            (watched_files, _watcher) = watch_files(&config_file, &config, file_tx.clone());
+                        }
+                        // Hand the new config to the checks as well, they are
+                        // left running when nothing changed
+                        if changed {
+                            config_tx.send_replace(config.clone());
+                        } else {
+                            debug!("Config is unchanged");
+                        }
+                    }
                     Err(e) => {
                         error!("Error reading config file: {}", e);
                         warn!("Using old config");
-                        config.clone()
-                    }
-                };
-                config = new_config.clone();
-                match config_services(new_config).await {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("Error registering service: {}", e);
                     }
                 }
             }
-            Err(e) => error!("watch error: {:?}", e),
+            Trigger::Sync => {
+                // Several services often come back together
+                while let Ok(Some(_)) = tokio::time::timeout(CONFIG_DEBOUNCE, sync_rx.recv()).await {}
+                debug!("Service available again, syncing...");
+            }
+            Trigger::Tick if failures > 0 => debug!("Retrying sync..."),
+            Trigger::Tick => debug!("Reconciling services..."),
+            Trigger::Closed => {
+                error!("watch error: channel closed");
+                return;
+            }
+        }
+        match config_services(config.clone()).await {
+            Ok(report) => {
+                failures = 0;
+                if report.is_empty() {
+                    debug!("Services are in sync with Consul");
+                } else {
+                    info!("Reconciled services: {}", report);
+                }
+            }
+            Err(e) => {
+                error!("Error registering service: {}", e);
+                failures += 1;
+            }
         }
-        tokio::time::sleep(Duration::from_secs(15)).await;
     } 
 }
 
+// Managed services with the worst status of their checks, followed by the
+// services pulled from Consul because they are unavailable
+async fn list_services(config: config::Config) -> anyhow::Result<()> {
+    let client = config.consul.connect().await?;
+    let managed_services = client.get_managed_services().await?;
+    let checks = client.get_agent_checks().await?;
+    let unavailable_services = client.get_unavailable_services().await?;
+    let catalog_services = client.get_catalog_services().await?;
+    println!("{:<30} {:<20} {:<6} HEALTH", "SERVICE", "ADDRESS", "PORT");
+    for service in &managed_services {
+        let statuses: Vec<&str> = checks.iter()
+            .filter(|check| check.service_id == service.id)
+            .map(|check| check.status.as_str())
+            .collect();
+        let health = ["critical", "warning", "passing"].into_iter()
+            .find(|status| statuses.contains(status))
+            .unwrap_or("no checks");
+        // Registered with the agent but not synced to the servers yet
+        let catalog = if catalog_services.contains_key(&service.service) { "" } else { ", not in catalog" };
+        println!("{:<30} {:<20} {:<6} {}{}", service.id, service.address, service.port, health, catalog);
+    }
+    for unavailable in &unavailable_services {
+        if managed_services.iter().any(|s| s.id == unavailable.id) {
+            continue;
+        }
+        println!(
+            "{:<30} {:<20} {:<6} unavailable since {} after {} failures: {}",
+            unavailable.id,
+            unavailable.address,
+            unavailable.port,
+            humantime_serde::re::humantime::format_rfc3339_seconds(unavailable.since),
+            unavailable.failures,
+            unavailable.last_error,
+        );
+    }
+    Ok(())
+}
+
+async fn purge_services(config: config::Config, all: bool) -> anyhow::Result<()> {
+    let client = config.consul.connect().await?;
+    // The sessions delete the unavailable keys they hold
+    for session in client.get_sessions(all).await? {
+        info!("Destroying session {}", session);
+        client.destroy_session(&session).await?;
+    }
+    let services = if all { client.get_tagged_services().await? } else { client.get_managed_services().await? };
+    for service in services {
+        info!("Purging service {}", service.id);
+        client.deregister_agent_service(&service.id).await?;
+        println!("- {}", service.id);
+    }
+    if all {
+        client.clear_all_unavailable_services().await?;
+    } else {
+        client.clear_unavailable_services().await?;
+    }
+    Ok(())
+}
+
+async fn run(config: config::Config, config_file: PathBuf) -> anyhow::Result<()> {
+    let (tx, rx) = unbounded_channel();
+    // Syncs asked for by the check loop, kept apart from file changes so they
+    // do not reload the config
+    let (sync_tx, sync_rx) = unbounded_channel();
+    match config_services(config.clone()).await {
+        Ok(report) => info!("Initial sync: {}", report),
+        Err(e) => {
+            error!("Error registering service: {}", e);
+            // Hand the failed sync over to the config loop so it gets retried
+            sync_tx.send(()).unwrap();
+        }
+    }
+
+    let (config_tx, config_rx) = watch::channel(config.clone());
+    // Still holds the config of the last reload once the loops are gone
+    let latest_config = config_rx.clone();
+    let (session_tx, session_rx) = watch::channel(None);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let shutdown_config_rx = shutdown_rx.clone();
+    let mut check_task = task::spawn(async move {
+        loop_check_services(config_rx, session_tx, sync_tx, shutdown_rx).await;
+    });
+    let mut config_task = task::spawn(async move {
+        loop_config_services(config, config_file, tx, rx, sync_rx, config_tx, shutdown_config_rx).await;
+    });
+    tokio::select! {
+        _ = &mut check_task => (),
+        _ = &mut config_task => (),
+        _ = shutdown_signal() => {
+            info!("Shutting down...");
+            // Let the loops finish the probe or sync in flight
+            shutdown_tx.send_replace(true);
+            let _ = tokio::join!(check_task, config_task);
+            let config = latest_config.borrow().clone();
+            if config.deregister_on_shutdown {
+                // The session deletes the unavailable keys it holds. Otherwise
+                // it is left to expire, so a restart within the TTL still knows
+                // which services were pulled
+                let session = session_rx.borrow().clone();
+                if let Some(id) = session {
+                    if let Err(e) = config.consul.connect().await?.destroy_session(&id).await {
+                        error!("Error destroying session {}: {}", id, e);
+                    }
+                }
+                shutdown_services(&config).await?;
+            }
+        },
+    }
+    Ok(())
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => debug!("Received SIGINT"),
+        _ = terminate.recv() => debug!("Received SIGTERM"),
+    }
+}
+
+// Remove everything this instance put in Consul so nothing points at a stopped host
+async fn shutdown_services(config: &config::Config) -> anyhow::Result<()> {
+    let client = config.consul.connect().await?;
+    for service in client.get_managed_services().await? {
+        info!("Deregistering service {} on shutdown", service.id);
+        if let Err(e) = client.deregister_agent_service(&service.id).await {
+            error!("Error deregistering service {}: {}", service.id, e);
+        }
+    }
+    client.clear_unavailable_services().await?;
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    let config_file = args.config;
-    let config_file_clone = config_file.clone();
+    let Some(config_file) = args.config else {
+        Args::command().error(ErrorKind::MissingRequiredArgument, "--config <CONFIG> is required").exit();
+    };
     let config = match config::read(&config_file) {
         Ok(config) => config,
         Err(e) => {
@@ -175,35 +682,30 @@ async fn main() -> anyhow::Result<()> {
         None => "info".to_string(),
     };
     let collector = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
         .with_max_level(log_level.parse::<Level>().unwrap_or(Level::INFO))
         .finish();
     tracing::subscriber::set_global_default(collector).expect("setting default subscriber failed");
     info!("Config is {:?}", config);
 
-    let (tx, rx) = mpsc::channel();
-    match config_services(config.clone()).await {
-        Ok(_) => (),
-        Err(e) => {
-            error!("Error registering service: {}", e);
-        }
-    }
-    let tx_clone = tx.clone();
-    thread::spawn(move || {
-        if let Err(err) = watch_config_file(&config_file_clone, tx) {
-            error!("Error monitoring config file changes: {}", err);
-        }
-    });
-
-    let config_clone = config.clone();
-    let check_task = task::spawn(async move {
-        loop_check_services(config_clone,tx_clone).await;
-    });
-    let config_task = task::spawn(async move {
-        loop_config_services(config, config_file, rx).await;
-    });
-    tokio::select! {
-        _ = check_task => (),
-        _ = config_task => (),
+    match args.command.unwrap_or(Command::Run) {
+        Command::Run => run(config, config_file).await,
+        Command::Apply => {
+            let report = config_services(config).await?;
+            println!("Applied: {}", report);
+            Ok(())
+        },
+        Command::Validate => {
+            println!("Config {:?} is valid, {} service(s)", config_file, config.services.len());
+            Ok(())
+        },
+        Command::Plan => {
+            if plan_services(config).await? {
+                std::process::exit(2);
+            }
+            Ok(())
+        },
+        Command::List => list_services(config).await,
+        Command::Purge { all } => purge_services(config, all).await,
     }
-    Ok(())
 }
@@ -0,0 +1,194 @@
+use std::fmt;
+
+use crate::config::ServiceConfig;
+use crate::consul::{AgentService, UnavailableService};
+
+#[derive(Debug)]
+pub enum Change {
+    Register(ServiceConfig),
+    Update(ServiceConfig, Vec<String>),
+    Deregister(String),
+}
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Register(service) => {
+                writeln!(f, "+ {}", service.id())?;
+                if service.id() != service.name {
+                    writeln!(f, "    name: {}", service.name)?;
+                }
+                writeln!(f, "    address: {}", service.address)?;
+                writeln!(f, "    port: {}", service.port)?;
+                for tag in &service.tags {
+                    writeln!(f, "    tags: + {}", tag)?;
+                }
+                Ok(())
+            },
+            Change::Update(service, diff) => {
+                writeln!(f, "~ {}", service.id())?;
+                for line in diff {
+                    writeln!(f, "    {}", line)?;
+                }
+                Ok(())
+            },
+            Change::Deregister(id) => writeln!(f, "- {}", id),
+        }
+    }
+}
+
+/// Compute the changes needed to bring the managed services in line with the config.
+/// Services listed as unavailable are left out until the check loop sees them again.
+pub fn plan(services: &[ServiceConfig], managed_services: &[AgentService], unavailable_services: &[UnavailableService]) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for service in managed_services {
+        if !services.iter().any(|s| s.id() == service.id) {
+            changes.push(Change::Deregister(service.id.clone()));
+        }
+    }
+    for service in services {
+        match managed_services.iter().find(|s| s.id == service.id()) {
+            Some(current) if current == service => continue,
+            Some(current) => changes.push(Change::Update(service.clone(), current.diff(service))),
+            None if unavailable_services.iter().any(|s| s.id == service.id()) => continue,
+            None => changes.push(Change::Register(service.clone())),
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CheckConfig, HttpProbe, Probe};
+    use crate::consul::Consul;
+    use std::time::{Duration, SystemTime};
+
+    fn service(id: &str, port: u16) -> ServiceConfig {
+        ServiceConfig {
+            name: id.to_string(),
+            port,
+            address: "10.0.0.1".to_string(),
+            tags: vec!["web".to_string()],
+            ..Default::default()
+        }
+    }
+
+    // What the agent returns for a service registered from `service`
+    fn registered(service: &ServiceConfig) -> AgentService {
+        let registration = Consul::default().registration(&service.clone().into());
+        AgentService {
+            kind: registration.kind,
+            id: registration.id,
+            service: registration.name,
+            tags: registration.tags,
+            meta: registration.meta,
+            port: registration.port,
+            address: registration.address,
+            tagged_addresses: registration.tagged_addresses,
+            weights: registration.weights.unwrap_or_default(),
+            enable_tag_override: registration.enable_tag_override,
+            datacenter: "dc1".to_string(),
+        }
+    }
+
+    fn unavailable(id: &str) -> UnavailableService {
+        UnavailableService {
+            id: id.to_string(),
+            since: SystemTime::now(),
+            last_error: "connection refused".to_string(),
+            failures: 3,
+            address: "10.0.0.1".to_string(),
+            port: 80,
+            version: "0.2.0".to_string(),
+        }
+    }
+
+    #[test]
+    fn services_in_sync_need_no_change() {
+        let services = vec![service("web", 80)];
+        let managed = vec![registered(&services[0])];
+        assert!(plan(&services, &managed, &[]).is_empty());
+    }
+
+    #[test]
+    fn missing_service_is_registered() {
+        let services = vec![service("web", 80)];
+        let changes = plan(&services, &[], &[]);
+        assert!(matches!(changes.as_slice(), [Change::Register(s)] if s.id() == "web"));
+    }
+
+    #[test]
+    fn service_removed_from_config_is_deregistered() {
+        let managed = vec![registered(&service("old", 80))];
+        let changes = plan(&[], &managed, &[]);
+        assert!(matches!(changes.as_slice(), [Change::Deregister(id)] if id == "old"));
+    }
+
+    #[test]
+    fn drifted_service_is_updated_with_its_diff() {
+        let services = vec![service("web", 81)];
+        let managed = vec![registered(&service("web", 80))];
+        let changes = plan(&services, &managed, &[]);
+        match changes.as_slice() {
+            [Change::Update(s, diff)] => {
+                assert_eq!(s.id(), "web");
+                // The TCP check follows the port
+                assert_eq!(diff, &vec![
+                    "port: 80 -> 81".to_string(),
+                    "check: tcp 10.0.0.1:81 every 10000ms timeout 5000ms".to_string(),
+                ]);
+            },
+            changes => panic!("unexpected changes {:?}", changes),
+        }
+    }
+
+    #[test]
+    fn unavailable_service_is_not_registered_again() {
+        let services = vec![service("web", 80), service("api", 8080)];
+        let changes = plan(&services, &[], &[unavailable("web")]);
+        assert!(matches!(changes.as_slice(), [Change::Register(s)] if s.id() == "api"));
+    }
+
+    #[test]
+    fn instances_are_matched_on_their_id() {
+        let first = ServiceConfig { id: Some("web-1".to_string()), ..service("web", 80) };
+        let second = ServiceConfig { id: Some("web-2".to_string()), ..service("web", 81) };
+        let managed = vec![registered(&first)];
+        let changes = plan(&[first, second], &managed, &[]);
+        assert!(matches!(changes.as_slice(), [Change::Register(s)] if s.id() == "web-2"));
+    }
+
+    #[test]
+    fn changed_check_is_an_update() {
+        let mut service = service("web", 80);
+        let managed = vec![registered(&service)];
+        service.check = Some(CheckConfig { interval: Duration::from_secs(30), ..CheckConfig::default() });
+        let changes = plan(&[service.clone()], &managed, &[]);
+        match changes.as_slice() {
+            [Change::Update(_, diff)] => assert_eq!(diff, &vec!["check: tcp 10.0.0.1:80 every 30000ms timeout 5000ms".to_string()]),
+            changes => panic!("unexpected changes {:?}", changes),
+        }
+        let http: HttpProbe = toml::from_str("path = \"/health\"").unwrap();
+        service.check = Some(CheckConfig { probe: Probe::Http(http), ..CheckConfig::default() });
+        assert!(matches!(plan(&[service], &managed, &[]).as_slice(), [Change::Update(..)]));
+    }
+
+    #[test]
+    fn switch_to_ttl_check_is_an_update() {
+        let mut service = service("web", 80);
+        let managed = vec![registered(&service)];
+        service.check = Some(CheckConfig { ttl: Some(Duration::from_secs(30)), ..CheckConfig::default() });
+        match plan(&[service], &managed, &[]).as_slice() {
+            [Change::Update(_, diff)] => assert_eq!(diff, &vec!["check: ttl 30000ms".to_string()]),
+            changes => panic!("unexpected changes {:?}", changes),
+        }
+    }
+
+    #[test]
+    fn service_registered_without_check_hash_is_updated() {
+        let service = service("web", 80);
+        let mut current = registered(&service);
+        current.meta.remove(crate::consul::CHECK_META);
+        assert!(matches!(plan(&[service], &[current], &[]).as_slice(), [Change::Update(..)]));
+    }
+}
@@ -0,0 +1,201 @@
+use minijinja::{context, Environment, UndefinedBehavior, Value};
+use std::collections::HashMap;
+
+use crate::config::{CheckConfig, KindConfig, Probe, ServiceConfig, TaggedAddress};
+use crate::consul::hostname;
+
+/// Renders the templated strings of a service and its kinds. Templates see
+/// `service.id`, `service.name`, `service.port`, `service.address`,
+/// `hostname`, `env` and the `[vars]` table, an unknown variable is an error
+pub struct Renderer {
+    env: Environment<'static>,
+    globals: Value,
+}
+
+impl Renderer {
+    pub fn new(vars: &HashMap<String, String>) -> Self {
+        let mut env = Environment::new();
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        let globals = context! {
+            hostname => hostname(),
+            env => std::env::vars().collect::<HashMap<String, String>>(),
+            vars => vars,
+        };
+        Renderer { env, globals }
+    }
+
+    /// Render before the kind is applied, so the tag precedence compares
+    /// rendered keys. Both see the port and address the service ends up with
+    pub fn render(&self, service: &mut ServiceConfig, kind: &mut KindConfig) -> anyhow::Result<()> {
+        let port = kind.port.unwrap_or(service.port);
+        let address = kind.address.as_ref().unwrap_or(&service.address);
+        let address = self.render_str(&service.name, address, &self.context(service, port, address))?;
+        match &mut kind.address {
+            Some(kind_address) => *kind_address = address.clone(),
+            None => service.address = address.clone(),
+        }
+        let ctx = self.context(service, port, &address);
+        let name = service.name.clone();
+        self.render_strings(&name, &mut service.tags, &mut service.meta, &mut service.tagged_addresses, &ctx)?;
+        if let Some(check) = &mut service.check {
+            self.render_check(&name, check, &ctx)?;
+        }
+        self.render_strings(&name, &mut kind.tags, &mut kind.meta, &mut kind.tagged_addresses, &ctx)?;
+        if let Some(check) = &mut kind.check {
+            self.render_check(&name, check, &ctx)?;
+        }
+        Ok(())
+    }
+
+    fn context(&self, service: &ServiceConfig, port: u16, address: &str) -> Value {
+        context! {
+            service => context! {
+                id => service.id(),
+                name => service.name,
+                port => port,
+                address => address,
+            },
+            ..self.globals.clone()
+        }
+    }
+
+    fn render_strings(
+        &self,
+        service: &str,
+        tags: &mut [String],
+        meta: &mut HashMap<String, String>,
+        tagged_addresses: &mut HashMap<String, TaggedAddress>,
+        ctx: &Value,
+    ) -> anyhow::Result<()> {
+        for tag in tags {
+            *tag = self.render_str(service, tag, ctx)?;
+        }
+        for value in meta.values_mut() {
+            *value = self.render_str(service, value, ctx)?;
+        }
+        for tagged_address in tagged_addresses.values_mut() {
+            tagged_address.address = self.render_str(service, &tagged_address.address, ctx)?;
+        }
+        Ok(())
+    }
+
+    fn render_check(&self, service: &str, check: &mut CheckConfig, ctx: &Value) -> anyhow::Result<()> {
+        match &mut check.probe {
+            Probe::Tcp => (),
+            Probe::Http(probe) => {
+                probe.path = self.render_str(service, &probe.path, ctx)?;
+                for value in probe.headers.values_mut() {
+                    *value = self.render_str(service, value, ctx)?;
+                }
+                if let Some(needle) = &mut probe.body_contains {
+                    *needle = self.render_str(service, needle, ctx)?;
+                }
+            },
+            Probe::Grpc(probe) => probe.service = self.render_str(service, &probe.service, ctx)?,
+            Probe::Command(probe) => {
+                for arg in &mut probe.command {
+                    *arg = self.render_str(service, arg, ctx)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    // Plain strings are left alone, Traefik rules and regexes use braces too
+    fn render_str(&self, service: &str, template: &str, ctx: &Value) -> anyhow::Result<String> {
+        if !template.contains("{{") && !template.contains("{%") {
+            return Ok(template.to_string());
+        }
+        self.env
+            .render_str(template, ctx)
+            .map_err(|e| anyhow::anyhow!("service {}: cannot render {:?}: {}", service, template, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service_from(toml: &str) -> ServiceConfig {
+        toml::from_str(&format!("name = \"blog\"\nport = 80\naddress = \"10.0.0.1\"\n{}", toml)).unwrap()
+    }
+
+    fn kind_from(toml: &str) -> KindConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn renderer() -> Renderer {
+        Renderer::new(&HashMap::from([("domain".to_string(), "example.org".to_string())]))
+    }
+
+    #[test]
+    fn undefined_variables_are_errors() {
+        let mut service = service_from("tags = [\"{{ vars.missing }}\"]");
+        let error = renderer().render(&mut service, &mut KindConfig::default()).unwrap_err().to_string();
+        assert!(error.starts_with("service blog: cannot render \"{{ vars.missing }}\""), "{}", error);
+        let mut service = service_from("tags = [\"{{ service.nope }}\"]");
+        assert!(renderer().render(&mut service, &mut KindConfig::default()).is_err());
+    }
+
+    #[test]
+    fn strings_without_template_tags_are_left_alone() {
+        let traefik = "traefik.http.routers.blog.rule=Host(`{host:[a-z]+}.example.org`)";
+        let mut service = service_from(&format!("tags = [{:?}, \"{{#}}\"]", traefik));
+        let mut kind = kind_from("meta = { regex = \"^[0-9]{2,4}$\" }");
+        renderer().render(&mut service, &mut kind).unwrap();
+        assert_eq!(service.tags, vec![traefik.to_string(), "{#}".to_string()]);
+        assert_eq!(kind.meta["regex"], "^[0-9]{2,4}$");
+    }
+
+    #[test]
+    fn address_uses_the_port_and_address_of_the_kind() {
+        let address = "{{ service.name }}.{{ vars.domain }}:{{ service.port }}".to_string();
+        let mut service = ServiceConfig { address, ..service_from("") };
+        let mut kind = kind_from("port = 8080");
+        renderer().render(&mut service, &mut kind).unwrap();
+        assert_eq!(service.address, "blog.example.org:8080");
+
+        let mut service = service_from("tags = [\"url=http://{{ service.address }}:{{ service.port }}\"]");
+        let mut kind = kind_from("address = \"{{ service.name }}.internal\"");
+        renderer().render(&mut service, &mut kind).unwrap();
+        assert_eq!(service.address, "10.0.0.1");
+        assert_eq!(kind.address.as_deref(), Some("blog.internal"));
+        assert_eq!(service.tags, vec!["url=http://blog.internal:80".to_string()]);
+    }
+
+    #[test]
+    fn vars_env_and_hostname_are_available() {
+        let mut service = service_from(r#"
+            id = "blog-1"
+            tags = ["{{ vars.domain }}", "{{ env.CARGO_PKG_NAME }}", "{{ hostname }}", "{{ service.id }}"]
+        "#);
+        renderer().render(&mut service, &mut KindConfig::default()).unwrap();
+        assert_eq!(service.tags, vec!["example.org".to_string(), env!("CARGO_PKG_NAME").to_string(), hostname(), "blog-1".to_string()]);
+    }
+
+    #[test]
+    fn checks_meta_and_tagged_addresses_are_rendered() {
+        let mut service = service_from(r#"
+            meta = { url = "https://{{ service.name }}.{{ vars.domain }}" }
+            tagged_addresses = { wan = { address = "{{ service.name }}.{{ vars.domain }}", port = 443 } }
+            check = { type = "http", path = "/{{ service.name }}/health", headers = { Host = "{{ vars.domain }}" }, body_contains = "{{ service.name }} ok" }
+        "#);
+        let mut kind = kind_from(r#"
+            meta = { owner = "{{ vars.domain }}" }
+            check = { type = "command", command = ["check", "{{ service.address }}:{{ service.port }}"] }
+        "#);
+        renderer().render(&mut service, &mut kind).unwrap();
+        assert_eq!(service.meta["url"], "https://blog.example.org");
+        assert_eq!(service.tagged_addresses["wan"].address, "blog.example.org");
+        match &service.check.unwrap().probe {
+            Probe::Http(probe) => {
+                assert_eq!(probe.path, "/blog/health");
+                assert_eq!(probe.headers["Host"], "example.org");
+                assert_eq!(probe.body_contains.as_deref(), Some("blog ok"));
+            },
+            probe => panic!("unexpected probe {:?}", probe),
+        }
+        assert_eq!(kind.meta["owner"], "example.org");
+        assert!(matches!(&kind.check.unwrap().probe, Probe::Command(probe) if probe.command == ["check", "10.0.0.1:80"]));
+    }
+}